@@ -1,5 +1,7 @@
+use crate::shutdown::ShutdownState;
 use crate::tcp::TcpIncoming;
-use crate::{HttpIncoming, TcpOrTlsIncoming, TcpStream, TlsStream};
+use crate::{HttpIncoming, ShutdownSignal, TcpOrTlsIncoming, TcpStream, TlsStream};
+use async_io::Async;
 use futures::prelude::*;
 use futures::stream::FusedStream;
 use futures::StreamExt;
@@ -7,19 +9,50 @@ use rustls_acme::AcmeConfig;
 use std::convert::Infallible;
 use std::fmt::Debug;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
 use std::task::{Context, Poll};
 
 pub struct AcmeIncoming<EC: Debug + 'static, EA: Debug + 'static> {
     incoming: rustls_acme::Incoming<TcpStream, Infallible, TcpIncomingInfallible, EC, EA>,
+    stop_accepting: Arc<AtomicBool>,
+    handshakes: Handshakes,
+    shutdown: Option<ShutdownSignal>,
+    terminated: bool,
 }
 
+/// Connections passed on to [rustls_acme::Incoming] that have not been yielded as [TlsStream]
+/// yet. Failed handshakes drop their stream, so only live entries are in flight.
+type Handshakes = Arc<Mutex<Vec<Weak<Async<std::net::TcpStream>>>>>;
+
 impl<EC: Debug, EA: Debug> AcmeIncoming<EC, EA> {
     pub fn new(tcp_incoming: TcpIncoming, config: AcmeConfig<EC, EA>) -> Self {
-        let incoming = config.incoming(TcpIncomingInfallible(tcp_incoming), vec![]);
-        AcmeIncoming { incoming }
+        let stop_accepting = Arc::new(AtomicBool::new(false));
+        let handshakes = Handshakes::default();
+        let shutdown = tcp_incoming.shutdown.clone();
+        let tcp_incoming = TcpIncomingInfallible {
+            incoming: Some(tcp_incoming),
+            stop: stop_accepting.clone(),
+            handshakes: handshakes.clone(),
+        };
+        let incoming = config.incoming(tcp_incoming, vec![]);
+        AcmeIncoming {
+            incoming,
+            stop_accepting,
+            handshakes,
+            shutdown,
+            terminated: false,
+        }
+    }
+    /// Stop accepting connections once shutdown is triggered and terminate when all pending
+    /// handshakes are finished, or at the deadline abandoning them, see [Shutdown](crate::Shutdown).
+    pub fn graceful_shutdown(mut self, signal: ShutdownSignal) -> Self {
+        self.shutdown = Some(signal);
+        self
     }
     pub fn http(self) -> HttpIncoming<TlsStream, Self> {
-        HttpIncoming::new(self)
+        let shutdown = self.shutdown.clone();
+        HttpIncoming::new(self).with_optional_shutdown(shutdown)
     }
     pub fn or_tcp(self) -> TcpOrTlsIncoming {
         let mut tcp_or_tls = TcpOrTlsIncoming::new();
@@ -32,37 +65,87 @@ impl<EC: Debug, EA: Debug> Stream for AcmeIncoming<EC, EA> {
     type Item = TlsStream;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(shutdown) = &mut self.shutdown {
+            match shutdown.poll_state(cx) {
+                ShutdownState::Running => {}
+                ShutdownState::Draining => self.stop_accepting.store(true, Ordering::Relaxed),
+                ShutdownState::Expired => self.terminated = true,
+            }
+        }
+        if self.terminated {
+            return Poll::Ready(None);
+        }
         match self.incoming.poll_next_unpin(cx) {
-            Poll::Ready(Some(Ok(item))) => Poll::Ready(Some(item)),
+            Poll::Ready(Some(Ok(item))) => {
+                let tcp = Arc::downgrade(&Arc::from(async_net::TcpStream::clone(item.get_ref().0)));
+                self.handshakes
+                    .lock()
+                    .unwrap()
+                    .retain(|weak| !weak.ptr_eq(&tcp));
+                Poll::Ready(Some(item))
+            }
             Poll::Ready(Some(Err(_))) => unreachable!(),
             Poll::Ready(None) => Poll::Ready(None),
-            Poll::Pending => Poll::Pending,
+            Poll::Pending => {
+                if self.stop_accepting.load(Ordering::Relaxed) && self.handshakes_finished() {
+                    self.terminated = true;
+                    return Poll::Ready(None);
+                }
+                Poll::Pending
+            }
         }
     }
 }
 
+impl<EC: Debug, EA: Debug> AcmeIncoming<EC, EA> {
+    fn handshakes_finished(&self) -> bool {
+        let mut handshakes = self.handshakes.lock().unwrap();
+        handshakes.retain(|weak| weak.strong_count() > 0);
+        handshakes.is_empty()
+    }
+}
+
 impl<EC: Debug, EA: Debug> FusedStream for AcmeIncoming<EC, EA> {
     fn is_terminated(&self) -> bool {
-        self.incoming.is_terminated()
+        self.terminated || self.incoming.is_terminated()
     }
 }
 
-struct TcpIncomingInfallible(TcpIncoming);
+struct TcpIncomingInfallible {
+    incoming: Option<TcpIncoming>,
+    stop: Arc<AtomicBool>,
+    handshakes: Handshakes,
+}
 
 impl Stream for TcpIncomingInfallible {
     type Item = Result<TcpStream, Infallible>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        match self.0.poll_next_unpin(cx) {
-            Poll::Ready(Some(tcp)) => Poll::Ready(Some(Ok(tcp))),
-            Poll::Ready(None) => Poll::Ready(None),
-            Poll::Pending => Poll::Pending,
+        if self.stop.load(Ordering::Relaxed) {
+            drop(self.incoming.take());
+        }
+        // Never end the stream, since rustls_acme::Incoming keeps polling it after the end.
+        // Termination is handled by AcmeIncoming instead.
+        match &mut self.incoming {
+            Some(incoming) => match incoming.poll_next_unpin(cx) {
+                Poll::Ready(Some(tcp)) => {
+                    let weak = Arc::downgrade(&Arc::from(async_net::TcpStream::clone(&tcp)));
+                    self.handshakes.lock().unwrap().push(weak);
+                    Poll::Ready(Some(Ok(tcp)))
+                }
+                Poll::Ready(None) => {
+                    drop(self.incoming.take());
+                    Poll::Pending
+                }
+                Poll::Pending => Poll::Pending,
+            },
+            None => Poll::Pending,
         }
     }
 }
 
 impl FusedStream for TcpIncomingInfallible {
     fn is_terminated(&self) -> bool {
-        self.0.is_terminated()
+        false
     }
 }
@@ -0,0 +1,379 @@
+use crate::{Http2Incoming, HttpIncoming, PeerAddr, ShutdownSignal, TlsStream};
+use futures::prelude::*;
+use futures::stream::FusedStream;
+use futures::task::{waker, ArcWake};
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// Splits a stream of TLS connections into per-protocol streams based on the negotiated ALPN
+/// protocol, see [TlsIncoming::dispatch_alpn](crate::TlsIncoming::dispatch_alpn).
+///
+/// Connections without a negotiated protocol or with a protocol not having its own route are
+/// passed to the [fallback](Self::fallback) route, or closed if there is none. The routes share
+/// the underlying stream, so each route must be polled for the others to make progress.
+/// Connections are queued per route until it is polled, closing further connections for a route
+/// once its queue is full (see [Self::max_queued]).
+///
+/// ```no_run
+/// # use async_web_server::{parse_pem, TcpIncoming};
+/// # let (cert_chain, key_der) = parse_pem(std::fs::read("cert.pem")?)?;
+/// let dispatcher = TcpIncoming::bind(([0, 0, 0, 0], 443))?
+///     .tls(cert_chain, key_der)
+///     .unwrap()
+///     .alpn_protocols(["x-custom/1", "http/1.1"])
+///     .dispatch_alpn();
+/// let custom = dispatcher.route("x-custom/1");
+/// let http = dispatcher.fallback().http();
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct AlpnDispatcher<IO, S: Stream<Item = TlsStream<IO>> + Unpin> {
+    shared: Arc<Mutex<Shared<IO, S>>>,
+    wakers: Arc<Wakers>,
+    shutdown: Option<ShutdownSignal>,
+}
+
+struct Shared<IO, S> {
+    incoming: Option<S>,
+    routes: HashMap<Option<Vec<u8>>, Route<IO>>,
+    next_id: usize,
+    max_queued: usize,
+}
+
+struct Route<IO> {
+    queue: VecDeque<TlsStream<IO>>,
+    streams: usize,
+}
+
+/// Wakes every route when the shared stream makes progress, as any of them may be waiting for it.
+#[derive(Default)]
+struct Wakers(Mutex<HashMap<usize, Waker>>);
+
+impl Wakers {
+    fn register(&self, id: usize, new: &Waker) {
+        let mut wakers = self.0.lock().unwrap();
+        match wakers.get(&id) {
+            Some(waker) if waker.will_wake(new) => {}
+            _ => drop(wakers.insert(id, new.clone())),
+        }
+    }
+}
+
+impl ArcWake for Wakers {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        for waker in arc_self.0.lock().unwrap().values() {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+impl<IO, S: Stream<Item = TlsStream<IO>> + Unpin> AlpnDispatcher<IO, S> {
+    pub fn new(incoming: S) -> Self {
+        AlpnDispatcher {
+            shared: Arc::new(Mutex::new(Shared {
+                incoming: Some(incoming),
+                routes: HashMap::new(),
+                next_id: 0,
+                max_queued: 64,
+            })),
+            wakers: Arc::new(Wakers::default()),
+            shutdown: None,
+        }
+    }
+    pub(crate) fn with_optional_shutdown(mut self, signal: Option<ShutdownSignal>) -> Self {
+        self.shutdown = signal;
+        self
+    }
+    /// Limit the number of connections waiting for a route to be polled (64 by default). Further
+    /// connections for a route with a full queue are closed.
+    pub fn max_queued(self, max: usize) -> Self {
+        self.shared.lock().unwrap().max_queued = max;
+        self
+    }
+    /// Connections that negotiated the given protocol.
+    pub fn route(&self, protocol: impl AsRef<[u8]>) -> AlpnIncoming<IO, S> {
+        self.add_route(Some(protocol.as_ref().to_vec()))
+    }
+    /// Connections without a negotiated protocol or with a protocol lacking a route.
+    pub fn fallback(&self) -> AlpnIncoming<IO, S> {
+        self.add_route(None)
+    }
+    fn add_route(&self, protocol: Option<Vec<u8>>) -> AlpnIncoming<IO, S> {
+        let mut shared = self.shared.lock().unwrap();
+        let id = shared.next_id;
+        shared.next_id += 1;
+        let route = shared.routes.entry(protocol.clone()).or_insert(Route {
+            queue: VecDeque::new(),
+            streams: 0,
+        });
+        route.streams += 1;
+        AlpnIncoming {
+            id,
+            protocol,
+            shared: self.shared.clone(),
+            wakers: self.wakers.clone(),
+            shutdown: self.shutdown.clone(),
+        }
+    }
+}
+
+/// Connections routed to one protocol by an [AlpnDispatcher]. Streams for the same protocol
+/// share its connections.
+pub struct AlpnIncoming<IO, S: Stream<Item = TlsStream<IO>> + Unpin> {
+    id: usize,
+    protocol: Option<Vec<u8>>,
+    shared: Arc<Mutex<Shared<IO, S>>>,
+    wakers: Arc<Wakers>,
+    shutdown: Option<ShutdownSignal>,
+}
+
+impl<IO, S: Stream<Item = TlsStream<IO>> + Unpin> AlpnIncoming<IO, S> {
+    /// Negotiated protocol of the connections, `None` for the fallback route.
+    pub fn protocol(&self) -> Option<&[u8]> {
+        self.protocol.as_deref()
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin, S: Stream<Item = TlsStream<IO>> + Unpin>
+    AlpnIncoming<IO, S>
+{
+    pub fn http(self) -> HttpIncoming<TlsStream<IO>, Self> {
+        let shutdown = self.shutdown.clone();
+        HttpIncoming::new(self).with_optional_shutdown(shutdown)
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin + PeerAddr, S: Stream<Item = TlsStream<IO>> + Unpin>
+    AlpnIncoming<IO, S>
+{
+    /// Serve HTTP/2 on the connections of this route, typically the `h2` route.
+    pub fn http2(self) -> Http2Incoming<TlsStream<IO>, Self> {
+        let shutdown = self.shutdown.clone();
+        Http2Incoming::new(self).with_optional_shutdown(shutdown)
+    }
+}
+
+impl<IO, S: Stream<Item = TlsStream<IO>> + Unpin> Unpin for AlpnIncoming<IO, S> {}
+
+impl<IO, S: Stream<Item = TlsStream<IO>> + Unpin> Stream for AlpnIncoming<IO, S> {
+    type Item = TlsStream<IO>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        this.wakers.register(this.id, cx.waker());
+        let mut shared = this.shared.lock().unwrap();
+        let shared = &mut *shared;
+        loop {
+            if let Some(route) = shared.routes.get_mut(&this.protocol) {
+                if let Some(tls_stream) = route.queue.pop_front() {
+                    return Poll::Ready(Some(tls_stream));
+                }
+            }
+            let incoming = match &mut shared.incoming {
+                Some(incoming) => incoming,
+                None => return Poll::Ready(None),
+            };
+            let waker = waker(this.wakers.clone());
+            match incoming.poll_next_unpin(&mut Context::from_waker(&waker)) {
+                Poll::Ready(Some(tls_stream)) => {
+                    let protocol = tls_stream.get_ref().1.alpn_protocol().map(<[u8]>::to_vec);
+                    let key = match shared.routes.contains_key(&protocol) {
+                        true => protocol.clone(),
+                        false => None,
+                    };
+                    let max_queued = shared.max_queued;
+                    match shared.routes.get_mut(&key) {
+                        Some(route) if key != this.protocol && route.queue.len() >= max_queued => {
+                            log::debug!(
+                                "closing tls connection, queue for alpn protocol {:?} is full",
+                                key
+                            )
+                        }
+                        Some(route) => {
+                            route.queue.push_back(tls_stream);
+                            if key != this.protocol {
+                                waker.wake_by_ref();
+                            }
+                        }
+                        None => log::debug!(
+                            "closing tls connection without route for alpn protocol {:?}",
+                            protocol
+                        ),
+                    }
+                }
+                Poll::Ready(None) => {
+                    shared.incoming = None;
+                    waker.wake_by_ref();
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<IO, S: Stream<Item = TlsStream<IO>> + Unpin> FusedStream for AlpnIncoming<IO, S> {
+    fn is_terminated(&self) -> bool {
+        let shared = self.shared.lock().unwrap();
+        shared.incoming.is_none()
+            && match shared.routes.get(&self.protocol) {
+                Some(route) => route.queue.is_empty(),
+                None => true,
+            }
+    }
+}
+
+impl<IO, S: Stream<Item = TlsStream<IO>> + Unpin> Drop for AlpnIncoming<IO, S> {
+    fn drop(&mut self) {
+        self.wakers.0.lock().unwrap().remove(&self.id);
+        if let Ok(mut shared) = self.shared.lock() {
+            if let Some(route) = shared.routes.get_mut(&self.protocol) {
+                route.streams -= 1;
+                if route.streams == 0 {
+                    shared.routes.remove(&self.protocol);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TcpIncoming, TcpStream, TlsIncoming};
+    use async_io::Timer;
+    use futures::executor::block_on;
+    use futures::future::{join, select, Either};
+    use rustls_acme::futures_rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName};
+    use rustls_acme::futures_rustls::rustls::server::ClientHello;
+    use rustls_acme::futures_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+    use rustls_acme::futures_rustls::TlsConnector;
+    use std::convert::TryFrom;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    type Tls = TlsIncoming<Box<dyn FnMut(&ClientHello) -> Arc<ServerConfig>>>;
+
+    struct Server {
+        dispatcher: AlpnDispatcher<TcpStream, Tls>,
+        addr: SocketAddr,
+        roots: RootCertStore,
+    }
+
+    fn server(protocols: &[&str]) -> Server {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_der = CertificateDer::from(cert.serialize_der().unwrap());
+        let key_der = PrivatePkcs8KeyDer::from(cert.serialize_private_key_der());
+        let config =
+            crate::tls::single_cert_config(vec![cert_der.clone()], key_der.into(), None).unwrap();
+        let tcp = TcpIncoming::bind(([127, 0, 0, 1], 0)).unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let tls: Tls = tcp.tls_with_config(Box::new(move |_: &ClientHello| config.clone()));
+        let mut roots = RootCertStore::empty();
+        roots.add(cert_der).unwrap();
+        Server {
+            dispatcher: tls.alpn_protocols(protocols).dispatch_alpn(),
+            addr,
+            roots,
+        }
+    }
+
+    impl Server {
+        /// Complete a handshake offering `protocols`, while polling `incoming` for a connection.
+        async fn connect<S: Stream + Unpin>(
+            &self,
+            protocols: &[&str],
+            incoming: &mut S,
+        ) -> Option<S::Item> {
+            let mut config = ClientConfig::builder()
+                .with_root_certificates(self.roots.clone())
+                .with_no_client_auth();
+            config.alpn_protocols = protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+            let client = async {
+                let tcp = async_net::TcpStream::connect(self.addr).await.unwrap();
+                let server_name = ServerName::try_from("localhost").unwrap();
+                let connector = TlsConnector::from(Arc::new(config));
+                connector.connect(server_name, tcp).await.unwrap()
+            };
+            join(client, next(incoming)).await.1
+        }
+    }
+
+    /// Next item, unless none is available before a short timeout.
+    async fn next<S: Stream + Unpin>(incoming: &mut S) -> Option<S::Item> {
+        let timer = Timer::after(Duration::from_millis(300));
+        match select(incoming.next(), timer).await {
+            Either::Left((item, _)) => item,
+            Either::Right(_) => None,
+        }
+    }
+
+    fn alpn(tls_stream: Option<TlsStream<TcpStream>>) -> Option<Vec<u8>> {
+        tls_stream
+            .unwrap()
+            .get_ref()
+            .1
+            .alpn_protocol()
+            .map(<[u8]>::to_vec)
+    }
+
+    #[test]
+    fn two_protocols() {
+        let server = server(&["a", "b"]);
+        let mut a = server.dispatcher.route("a");
+        let mut b = server.dispatcher.route("b");
+        assert_eq!(a.protocol(), Some(&b"a"[..]));
+        block_on(async {
+            assert_eq!(
+                alpn(server.connect(&["b"], &mut b).await),
+                Some(b"b".to_vec())
+            );
+            assert_eq!(
+                alpn(server.connect(&["a"], &mut a).await),
+                Some(b"a".to_vec())
+            );
+            // Connections for other routes are queued while polling one route.
+            assert!(server.connect(&["a"], &mut b).await.is_none());
+            assert_eq!(alpn(next(&mut a).await), Some(b"a".to_vec()));
+        });
+    }
+
+    #[test]
+    fn fallback() {
+        let server = server(&["a", "b"]);
+        let mut a = server.dispatcher.route("a");
+        let mut fallback = server.dispatcher.fallback();
+        assert_eq!(fallback.protocol(), None);
+        block_on(async {
+            assert_eq!(
+                alpn(server.connect(&["b"], &mut fallback).await),
+                Some(b"b".to_vec())
+            );
+            assert_eq!(alpn(server.connect(&[], &mut fallback).await), None);
+            assert_eq!(
+                alpn(server.connect(&["a"], &mut a).await),
+                Some(b"a".to_vec())
+            );
+        });
+        drop(fallback);
+        block_on(async {
+            // Without fallback route, connections without route are closed.
+            assert!(server.connect(&["b"], &mut a).await.is_none());
+            assert!(next(&mut a).await.is_none());
+        });
+    }
+
+    #[test]
+    fn bounded_queue() {
+        let mut server = server(&["a", "b"]);
+        server.dispatcher = server.dispatcher.max_queued(1);
+        let mut a = server.dispatcher.route("a");
+        let mut b = server.dispatcher.route("b");
+        block_on(async {
+            assert!(server.connect(&["a"], &mut b).await.is_none());
+            assert!(server.connect(&["a"], &mut b).await.is_none());
+            assert_eq!(alpn(next(&mut a).await), Some(b"a".to_vec()));
+            assert!(next(&mut a).await.is_none());
+        });
+    }
+}
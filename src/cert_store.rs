@@ -0,0 +1,308 @@
+use crate::parse_pem;
+use crate::tls::certified_key;
+use rustls_acme::futures_rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls_acme::futures_rustls::rustls;
+use rustls_acme::futures_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use rustls_acme::futures_rustls::rustls::sign::CertifiedKey;
+use rustls_acme::futures_rustls::rustls::ServerConfig;
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+/// Certificates keyed by hostname, selected using the server name (SNI) sent by the client, see
+/// [TcpIncoming::tls_with_store](crate::TcpIncoming::tls_with_store).
+///
+/// Names of the form `*.example.com` match exactly one additional label, so `a.example.com` but
+/// neither `example.com` nor `a.b.example.com`. Exact names take precedence over wildcards.
+/// Clients not sending a server name or asking for an unknown one get the default certificate,
+/// or fail the handshake if there is none.
+///
+/// ```no_run
+/// # use async_web_server::{CertStore, TcpIncoming};
+/// let store = CertStore::new()
+///     .add_pem(std::fs::read("example.com.pem")?)?
+///     .add_pem(std::fs::read("example.org.pem")?)?
+///     .default_pem(std::fs::read("fallback.pem")?)?;
+/// let incoming = TcpIncoming::bind(([0, 0, 0, 0], 443))?.tls_with_store(store);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CertStore {
+    exact: HashMap<String, Arc<CertifiedKey>>,
+    wildcard: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl CertStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Serve a certificate for the DNS names listed in its subject alternative names, or its
+    /// common name if there are none.
+    pub fn add(
+        self,
+        cert_chain: Vec<CertificateDer<'static>>,
+        key_der: PrivateKeyDer<'static>,
+    ) -> Result<Self, rustls::Error> {
+        let names = match cert_chain.first() {
+            Some(cert) => certificate_dns_names(cert)?,
+            None => return Err(rustls::Error::General("missing certificates".into())),
+        };
+        if names.is_empty() {
+            return Err(rustls::Error::General(
+                "certificate contains no dns names".into(),
+            ));
+        }
+        self.add_with_names(names, cert_chain, key_der)
+    }
+    /// Serve a certificate for the given names, regardless of the names it contains.
+    pub fn add_with_names(
+        mut self,
+        names: impl IntoIterator<Item = impl AsRef<str>>,
+        cert_chain: Vec<CertificateDer<'static>>,
+        key_der: PrivateKeyDer<'static>,
+    ) -> Result<Self, rustls::Error> {
+        let key = certified_key(cert_chain, key_der)?;
+        for name in names {
+            let name = normalize(name.as_ref());
+            match name.strip_prefix("*.") {
+                Some(suffix) => self.wildcard.insert(suffix.to_string(), key.clone()),
+                None => self.exact.insert(name, key.clone()),
+            };
+        }
+        Ok(self)
+    }
+    /// Like [Self::add], reading the certificate chain and key from PEM using [parse_pem].
+    pub fn add_pem(self, pem: impl AsRef<[u8]>) -> io::Result<Self> {
+        let (cert_chain, key_der) = parse_pem(pem)?;
+        self.add(cert_chain, key_der)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+    /// Certificate used if no other certificate matches the requested server name.
+    pub fn default_cert(
+        mut self,
+        cert_chain: Vec<CertificateDer<'static>>,
+        key_der: PrivateKeyDer<'static>,
+    ) -> Result<Self, rustls::Error> {
+        self.default = Some(certified_key(cert_chain, key_der)?);
+        Ok(self)
+    }
+    /// Like [Self::default_cert], reading the certificate chain and key from PEM using [parse_pem].
+    pub fn default_pem(self, pem: impl AsRef<[u8]>) -> io::Result<Self> {
+        let (cert_chain, key_der) = parse_pem(pem)?;
+        self.default_cert(cert_chain, key_der)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.wildcard.is_empty() && self.default.is_none()
+    }
+    /// Certificate served for a server name, falling back to the default certificate.
+    pub fn get(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        if let Some(name) = server_name {
+            let name = normalize(name);
+            if let Some(key) = self.exact.get(&name) {
+                return Some(key.clone());
+            }
+            if let Some((label, suffix)) = name.split_once('.') {
+                if let Some(key) = self.wildcard.get(suffix).filter(|_| !label.is_empty()) {
+                    return Some(key.clone());
+                }
+            }
+        }
+        self.default.clone()
+    }
+    /// Server configuration resolving certificates from this store.
+    pub fn server_config(self) -> Arc<ServerConfig> {
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(self));
+        Arc::new(config)
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.get(client_hello.server_name())
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn certificate_dns_names(cert: &CertificateDer) -> Result<Vec<String>, rustls::Error> {
+    let (_, cert) = X509Certificate::from_der(cert.as_ref())
+        .map_err(|err| rustls::Error::General(format!("invalid certificate: {}", err)))?;
+    let mut names = Vec::new();
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in san.value.general_names.iter() {
+            if let GeneralName::DNSName(dns) = name {
+                names.push(dns.to_string());
+            }
+        }
+    }
+    if names.is_empty() {
+        names.extend(
+            cert.subject()
+                .iter_common_name()
+                .filter_map(|cn| cn.as_str().ok())
+                .map(str::to_string),
+        );
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{CertificateParams, DistinguishedName, DnType};
+    use rustls_acme::futures_rustls::pki_types::PrivatePkcs8KeyDer;
+
+    fn generate(
+        params: CertificateParams,
+    ) -> (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>) {
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        (
+            vec![CertificateDer::from(cert.serialize_der().unwrap())],
+            PrivatePkcs8KeyDer::from(cert.serialize_private_key_der()).into(),
+        )
+    }
+
+    fn cert(names: &[&str]) -> (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>) {
+        generate(CertificateParams::new(
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>(),
+        ))
+    }
+
+    fn served(store: &CertStore, server_name: Option<&str>) -> Option<CertificateDer<'static>> {
+        store.get(server_name).map(|key| key.cert[0].clone())
+    }
+
+    #[test]
+    fn exact_names() {
+        let (a, a_key) = cert(&["a.example.com", "example.com"]);
+        let (b, b_key) = cert(&["b.example.com"]);
+        let store = CertStore::new()
+            .add(a.clone(), a_key)
+            .unwrap()
+            .add(b.clone(), b_key)
+            .unwrap();
+        assert_eq!(served(&store, Some("a.example.com")), Some(a[0].clone()));
+        assert_eq!(served(&store, Some("example.com")), Some(a[0].clone()));
+        assert_eq!(served(&store, Some("B.Example.COM.")), Some(b[0].clone()));
+        assert_eq!(served(&store, Some("c.example.com")), None);
+        assert_eq!(served(&store, Some("com")), None);
+        assert_eq!(served(&store, None), None);
+    }
+
+    #[test]
+    fn wildcard_matches_one_label() {
+        let (wildcard, wildcard_key) = cert(&["*.example.com"]);
+        let store = CertStore::new()
+            .add(wildcard.clone(), wildcard_key)
+            .unwrap();
+        assert_eq!(
+            served(&store, Some("a.example.com")),
+            Some(wildcard[0].clone())
+        );
+        assert_eq!(
+            served(&store, Some("A.EXAMPLE.COM")),
+            Some(wildcard[0].clone())
+        );
+        assert_eq!(served(&store, Some("example.com")), None);
+        assert_eq!(served(&store, Some("a.b.example.com")), None);
+        assert_eq!(served(&store, Some(".example.com")), None);
+        assert_eq!(served(&store, Some("aexample.com")), None);
+    }
+
+    #[test]
+    fn exact_takes_precedence() {
+        let (wildcard, wildcard_key) = cert(&["*.example.com"]);
+        let (exact, exact_key) = cert(&["a.example.com"]);
+        let store = CertStore::new()
+            .add(exact.clone(), exact_key)
+            .unwrap()
+            .add(wildcard.clone(), wildcard_key)
+            .unwrap();
+        assert_eq!(
+            served(&store, Some("a.example.com")),
+            Some(exact[0].clone())
+        );
+        assert_eq!(
+            served(&store, Some("b.example.com")),
+            Some(wildcard[0].clone())
+        );
+    }
+
+    #[test]
+    fn default_fallback() {
+        let (exact, exact_key) = cert(&["example.com"]);
+        let (default, default_key) = cert(&["fallback.test"]);
+        let store = CertStore::new()
+            .add(exact.clone(), exact_key)
+            .unwrap()
+            .default_cert(default.clone(), default_key)
+            .unwrap();
+        assert_eq!(served(&store, Some("example.com")), Some(exact[0].clone()));
+        assert_eq!(
+            served(&store, Some("example.org")),
+            Some(default[0].clone())
+        );
+        assert_eq!(
+            served(&store, Some("fallback.test")),
+            Some(default[0].clone())
+        );
+        assert_eq!(served(&store, None), Some(default[0].clone()));
+        assert!(CertStore::new().is_empty());
+        assert!(!store.is_empty());
+    }
+
+    #[test]
+    fn explicit_names() {
+        let (chain, key) = cert(&["example.com"]);
+        let store = CertStore::new()
+            .add_with_names(["*.example.org", "example.net."], chain.clone(), key)
+            .unwrap();
+        assert_eq!(
+            served(&store, Some("a.example.org")),
+            Some(chain[0].clone())
+        );
+        assert_eq!(served(&store, Some("example.net")), Some(chain[0].clone()));
+        assert_eq!(served(&store, Some("example.com")), None);
+    }
+
+    #[test]
+    fn common_name_fallback() {
+        let mut params = CertificateParams::new(Vec::new());
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "cn.example.com");
+        let (chain, key) = generate(params);
+        let store = CertStore::new().add(chain.clone(), key).unwrap();
+        assert_eq!(
+            served(&store, Some("cn.example.com")),
+            Some(chain[0].clone())
+        );
+    }
+
+    #[test]
+    fn invalid_certificates() {
+        let (_, key) = cert(&["example.com"]);
+        assert!(CertStore::new().add(Vec::new(), key).is_err());
+        let (_, key) = cert(&["example.com"]);
+        let garbage = vec![CertificateDer::from(vec![0x30, 0x03, 0x02])];
+        assert!(CertStore::new().add(garbage, key).is_err());
+        let mut params = CertificateParams::new(Vec::new());
+        params.distinguished_name = DistinguishedName::new();
+        let (chain, key) = generate(params);
+        assert!(CertStore::new().add(chain, key).is_err());
+        let (chain, _) = cert(&["example.com"]);
+        let (_, other_key) = cert(&["example.com"]);
+        assert!(CertStore::new().add(chain, other_key).is_err());
+    }
+}
@@ -0,0 +1,246 @@
+use crate::TlsStream;
+use rustls_acme::futures_rustls::pki_types::{CertificateDer, CertificateRevocationListDer};
+use rustls_acme::futures_rustls::rustls;
+use rustls_acme::futures_rustls::rustls::server::danger::ClientCertVerifier;
+use rustls_acme::futures_rustls::rustls::server::WebPkiClientVerifier;
+use rustls_acme::futures_rustls::rustls::RootCertStore;
+use rustls_pemfile::Item;
+use std::convert::TryFrom;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+/// Client certificate authentication for TLS listeners, see
+/// [TcpIncoming::tls_with_client_auth](crate::TcpIncoming::tls_with_client_auth).
+pub struct ClientAuth {
+    roots: RootCertStore,
+    crls: Vec<CertificateRevocationListDer<'static>>,
+    optional: bool,
+}
+
+impl ClientAuth {
+    /// Trust client certificates issued by the CAs in a PEM bundle. Clients without a
+    /// certificate are rejected unless [Self::optional] is enabled.
+    pub fn from_pem(ca_pem: impl AsRef<[u8]>) -> io::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        let mut buf = ca_pem.as_ref();
+        for item in rustls_pemfile::read_all(&mut buf)? {
+            if let Item::X509Certificate(der) = item {
+                roots
+                    .add(CertificateDer::from(der))
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            }
+        }
+        if roots.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "missing ca certificates",
+            ));
+        }
+        Ok(Self::from_roots(roots))
+    }
+    pub fn from_roots(roots: RootCertStore) -> Self {
+        ClientAuth {
+            roots,
+            crls: Vec::new(),
+            optional: false,
+        }
+    }
+    /// Also accept clients not presenting a certificate (disabled by default). Certificates that
+    /// are presented must still be valid.
+    pub fn optional(mut self, optional: bool) -> Self {
+        self.optional = optional;
+        self
+    }
+    /// Reject client certificates revoked by any CRL in a PEM bundle.
+    pub fn crls_pem(mut self, pem: impl AsRef<[u8]>) -> io::Result<Self> {
+        let mut buf = pem.as_ref();
+        for item in rustls_pemfile::read_all(&mut buf)? {
+            if let Item::Crl(der) = item {
+                self.crls.push(CertificateRevocationListDer::from(der));
+            }
+        }
+        Ok(self)
+    }
+    /// Reject client certificates revoked by the given DER encoded CRL.
+    pub fn crl(mut self, crl: CertificateRevocationListDer<'static>) -> Self {
+        self.crls.push(crl);
+        self
+    }
+    pub fn verifier(&self) -> Result<Arc<dyn ClientCertVerifier>, rustls::Error> {
+        let mut builder = WebPkiClientVerifier::builder(Arc::new(self.roots.clone()))
+            .with_crls(self.crls.iter().cloned());
+        if self.optional {
+            builder = builder.allow_unauthenticated();
+        }
+        builder
+            .build()
+            .map_err(|err| rustls::Error::General(format!("client verifier: {:?}", err)))
+    }
+}
+
+/// Identity fields of a client certificate.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ClientIdentity {
+    /// Distinguished name of the subject, e.g. `CN=service-a, O=Example`.
+    pub subject: String,
+    pub common_name: Option<String>,
+    pub dns_names: Vec<String>,
+    pub emails: Vec<String>,
+    pub uris: Vec<String>,
+    pub ip_addresses: Vec<IpAddr>,
+}
+
+impl ClientIdentity {
+    pub fn parse(cert: &CertificateDer) -> io::Result<Self> {
+        let (_, cert) = X509Certificate::from_der(cert.as_ref())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        let mut identity = ClientIdentity {
+            subject: cert.subject().to_string(),
+            common_name: cert
+                .subject()
+                .iter_common_name()
+                .find_map(|cn| cn.as_str().ok())
+                .map(str::to_string),
+            ..ClientIdentity::default()
+        };
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in san.value.general_names.iter() {
+                match name {
+                    GeneralName::DNSName(dns) => identity.dns_names.push(dns.to_string()),
+                    GeneralName::RFC822Name(email) => identity.emails.push(email.to_string()),
+                    GeneralName::URI(uri) => identity.uris.push(uri.to_string()),
+                    GeneralName::IPAddress(ip) => match ip.len() {
+                        4 => identity
+                            .ip_addresses
+                            .push(IpAddr::from(<[u8; 4]>::try_from(*ip).unwrap())),
+                        16 => identity
+                            .ip_addresses
+                            .push(IpAddr::from(<[u8; 16]>::try_from(*ip).unwrap())),
+                        _ => {}
+                    },
+                    _ => {}
+                }
+            }
+        }
+        Ok(identity)
+    }
+}
+
+pub trait ClientCertificate {
+    /// Client certificate chain verified during the TLS handshake, end-entity certificate first.
+    fn peer_certificates(&self) -> Option<&[CertificateDer<'_>]>;
+    /// Identity parsed from the end-entity client certificate.
+    fn client_identity(&self) -> Option<ClientIdentity> {
+        ClientIdentity::parse(self.peer_certificates()?.first()?).ok()
+    }
+}
+
+impl<IO> ClientCertificate for TlsStream<IO> {
+    fn peer_certificates(&self) -> Option<&[CertificateDer<'_>]> {
+        self.get_ref().1.peer_certificates()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType,
+        ExtendedKeyUsagePurpose, IsCa, SanType,
+    };
+    use rustls_acme::futures_rustls::pki_types::UnixTime;
+
+    fn ca() -> Certificate {
+        let mut params = CertificateParams::new(Vec::new());
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "Example CA");
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        Certificate::from_params(params).unwrap()
+    }
+
+    fn client(common_name: &str) -> CertificateParams {
+        let mut params = CertificateParams::new(Vec::new());
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        params
+    }
+
+    fn signed(params: CertificateParams, ca: &Certificate) -> CertificateDer<'static> {
+        let cert = Certificate::from_params(params).unwrap();
+        CertificateDer::from(cert.serialize_der_with_signer(ca).unwrap())
+    }
+
+    #[test]
+    fn identity() {
+        let mut params = client("service-a");
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, "Example");
+        params.subject_alt_names = vec![
+            SanType::DnsName("a.example.com".to_string()),
+            SanType::Rfc822Name("a@example.com".to_string()),
+            SanType::URI("spiffe://example.com/a".to_string()),
+            SanType::IpAddress([192, 0, 2, 1].into()),
+            SanType::IpAddress("2001:db8::1".parse().unwrap()),
+        ];
+        let identity = ClientIdentity::parse(&signed(params, &ca())).unwrap();
+        assert_eq!(identity.common_name.as_deref(), Some("service-a"));
+        assert!(identity.subject.contains("CN=service-a"));
+        assert!(identity.subject.contains("O=Example"));
+        assert_eq!(identity.dns_names, ["a.example.com"]);
+        assert_eq!(identity.emails, ["a@example.com"]);
+        assert_eq!(identity.uris, ["spiffe://example.com/a"]);
+        assert_eq!(
+            identity.ip_addresses,
+            [
+                IpAddr::from([192, 0, 2, 1]),
+                "2001:db8::1".parse::<IpAddr>().unwrap()
+            ]
+        );
+        let err = ClientIdentity::parse(&CertificateDer::from(vec![0u8; 4])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn identity_without_names() {
+        let mut params = CertificateParams::default();
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, "Example");
+        let identity = ClientIdentity::parse(&signed(params, &ca())).unwrap();
+        assert_eq!(identity.subject, "O=Example");
+        assert_eq!(identity.common_name, None);
+        assert!(identity.dns_names.is_empty());
+        assert!(identity.ip_addresses.is_empty());
+    }
+
+    #[test]
+    fn required_and_optional() {
+        let ca = ca();
+        let client_auth = ClientAuth::from_pem(ca.serialize_pem().unwrap()).unwrap();
+        let required = client_auth.verifier().unwrap();
+        assert!(required.offer_client_auth());
+        assert!(required.client_auth_mandatory());
+        let optional = client_auth.optional(true).verifier().unwrap();
+        assert!(optional.offer_client_auth());
+        assert!(!optional.client_auth_mandatory());
+
+        let now = UnixTime::now();
+        let trusted = signed(client("service-a"), &ca);
+        assert!(optional.verify_client_cert(&trusted, &[], now).is_ok());
+        let untrusted = signed(client("service-b"), &self::ca());
+        assert!(optional.verify_client_cert(&untrusted, &[], now).is_err());
+    }
+
+    #[test]
+    fn missing_ca() {
+        let err = ClientAuth::from_pem("").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
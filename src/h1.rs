@@ -1,39 +1,171 @@
-use crate::{HttpOrWsIncoming, IsTls, TcpIncoming, TcpOrTlsIncoming, TcpOrTlsStream, TcpStream};
-use async_http_codec::internal::buffer_decode::BufferDecode;
+use crate::http2::{poll_send_data, Http2Exchange};
+use crate::shutdown::ShutdownState;
+use crate::{
+    ClientCertificate, HasTlsInfo, HttpOrWsIncoming, IsTls, PeerAddr, ProxyHeader, ProxyInfo,
+    ShutdownSignal, TcpIncoming, TcpOrTlsIncoming, TcpOrTlsStream, TcpStream, TlsInfo,
+};
+#[cfg(unix)]
+use crate::{PeerCred, PeerCredentials};
+use async_http_codec::internal::buffer_decode::BufferDecodeState;
+use async_http_codec::internal::buffer_write::BufferWrite;
+use async_http_codec::internal::io_future::IoFutureWithOutputState;
 use async_http_codec::{
     BodyDecodeWithContinue, BodyDecodeWithContinueState, BodyEncode, RequestHead, ResponseHead,
 };
+use async_io::Timer;
+use bytes::Bytes;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::future::{select, Either};
 use futures::prelude::*;
+use futures::ready;
 use futures::stream::{FusedStream, FuturesUnordered};
 use futures::StreamExt;
-use http::header::{IntoHeaderName, HOST, LOCATION, TRANSFER_ENCODING};
+use h2::{Reason, SendStream};
+use http::header::HeaderName;
+use http::header::{IntoHeaderName, CONNECTION, CONTENT_LENGTH, HOST, LOCATION, TRANSFER_ENCODING};
 use http::uri::{Authority, Parts, Scheme};
 use http::{HeaderMap, HeaderValue, Method, Request, StatusCode, Uri, Version};
 use log::debug;
+use rustls_acme::futures_rustls::pki_types::CertificateDer;
 use std::borrow::Cow;
 use std::convert::TryFrom;
 use std::io;
+use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 pub struct HttpIncoming<
     IO: AsyncRead + AsyncWrite + Unpin = TcpOrTlsStream,
     T: Stream<Item = IO> + Unpin = TcpOrTlsIncoming,
 > {
     incoming: Option<T>,
-    decoding: FuturesUnordered<BufferDecode<IO, RequestHead<'static>>>,
+    decoding: FuturesUnordered<RequestHeadDecode<IO>>,
+    reuse_sender: Option<UnboundedSender<(IO, usize)>>,
+    reuse_receiver: UnboundedReceiver<(IO, usize)>,
+    max_requests_per_connection: Option<usize>,
+    max_pending_heads: Option<usize>,
+    timeouts: Timeouts,
+    timeout_counter: TimeoutCounter,
+    shutdown: Option<ShutdownSignal>,
+    requests: Option<RequestSource<IO>>,
 }
 
+/// Requests received other than by decoding a request head from a transport, i.e. over HTTP/2.
+type RequestSource<IO> = Pin<Box<dyn Stream<Item = HttpRequest<IO>> + Send + Sync>>;
+
 impl<IO: AsyncRead + AsyncWrite + Unpin, T: Stream<Item = IO> + Unpin> HttpIncoming<IO, T> {
     pub fn new(transport_incoming: T) -> Self {
+        let (reuse_sender, reuse_receiver) = unbounded();
         HttpIncoming {
             incoming: Some(transport_incoming),
             decoding: FuturesUnordered::new(),
+            reuse_sender: Some(reuse_sender),
+            reuse_receiver,
+            max_requests_per_connection: None,
+            max_pending_heads: None,
+            timeouts: Timeouts::default(),
+            timeout_counter: TimeoutCounter::default(),
+            shutdown: None,
+            requests: None,
         }
     }
+    /// Stop awaiting further requests on persistent connections once shutdown is triggered and
+    /// terminate after all pending request heads have been received or the deadline has passed,
+    /// see [Shutdown](crate::Shutdown).
+    /// The signal should also be attached to the underlying transport stream to stop accepting new
+    /// connections, which happens automatically when using the chaining methods such as
+    /// [TcpIncoming::http].
+    pub fn graceful_shutdown(mut self, signal: ShutdownSignal) -> Self {
+        self.shutdown = Some(signal);
+        self
+    }
+    pub(crate) fn with_optional_shutdown(mut self, signal: Option<ShutdownSignal>) -> Self {
+        self.shutdown = signal;
+        self
+    }
+    /// Also yield the requests of another source, polled regardless of [Self::max_pending_heads].
+    pub(crate) fn with_requests(mut self, requests: RequestSource<IO>) -> Self {
+        self.requests = Some(requests);
+        self
+    }
+    /// Limit the number of requests served on a single persistent connection (unlimited by
+    /// default). The response to the last permitted request includes `Connection: close`.
+    /// A limit of 1 disables keep-alive.
+    pub fn max_requests_per_connection(mut self, max: usize) -> Self {
+        self.max_requests_per_connection = Some(max);
+        self
+    }
+    /// Limit the number of request heads being received concurrently, including persistent
+    /// connections awaiting their next request (unlimited by default). While the limit is reached
+    /// no further connections are accepted, so it should be combined with [Self::idle_timeout].
+    pub fn max_pending_heads(mut self, max: usize) -> Self {
+        self.max_pending_heads = Some(max);
+        self
+    }
+    fn heads_exhausted(&self) -> bool {
+        match self.max_pending_heads {
+            Some(max) => self.decoding.len() >= max,
+            None => false,
+        }
+    }
+    /// Limit the time for receiving a request head (unlimited by default).
+    /// For a new connection the limit applies from accepting it, for a persistent connection from
+    /// receiving the first byte of the next request (see [Self::idle_timeout]).
+    pub fn head_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.head = Some(timeout);
+        self
+    }
+    /// Limit the time a persistent connection may remain idle between requests (unlimited by
+    /// default).
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.idle = Some(timeout);
+        self
+    }
+    /// Limit the time for receiving a request body, starting when reading it begins (unlimited by
+    /// default). Reading the body fails with [io::ErrorKind::TimedOut] once the limit is exceeded.
+    pub fn body_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.body = Some(timeout);
+        self
+    }
+    /// Attempt to send `408 Request Timeout` before closing connections exceeding the head or body
+    /// timeout (disabled by default). Idle connections are closed without response.
+    pub fn request_timeout_response(mut self, enable: bool) -> Self {
+        self.timeouts.respond = enable;
+        self
+    }
+    /// Counter of connections closed due to exceeding a timeout.
+    pub fn timeout_counter(&self) -> TimeoutCounter {
+        self.timeout_counter.clone()
+    }
     pub fn or_ws(self) -> HttpOrWsIncoming<IO, Self> {
         HttpOrWsIncoming::new(self)
     }
+    fn body_timeout_state(&self) -> Option<BodyTimeout> {
+        Some(BodyTimeout {
+            duration: self.timeouts.body?,
+            timer: None,
+            expired: false,
+            complete: false,
+            respond: self.timeouts.respond,
+            counter: self.timeout_counter.clone(),
+        })
+    }
+    fn keep_alive(&self, served: usize) -> Option<KeepAlive<IO>> {
+        let sender = self.reuse_sender.clone()?;
+        let last = match self.max_requests_per_connection {
+            Some(max) => served >= max,
+            None => false,
+        };
+        Some(KeepAlive {
+            sender,
+            served,
+            last,
+            shutdown: self.shutdown.clone(),
+        })
+    }
 }
 
 impl<IO: AsyncRead + AsyncWrite + Unpin, T: Stream<Item = IO> + Unpin> Stream
@@ -42,34 +174,83 @@ impl<IO: AsyncRead + AsyncWrite + Unpin, T: Stream<Item = IO> + Unpin> Stream
     type Item = HttpRequest<IO>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(shutdown) = &mut self.shutdown {
+            match shutdown.poll_state(cx) {
+                ShutdownState::Running => {}
+                ShutdownState::Draining => self.reuse_receiver.close(),
+                ShutdownState::Expired => {
+                    self.reuse_receiver.close();
+                    drop(self.incoming.take());
+                    drop(self.reuse_sender.take());
+                    drop(self.requests.take());
+                    self.decoding.clear();
+                }
+            }
+        }
+        if let Some(requests) = &mut self.requests {
+            match requests.poll_next_unpin(cx) {
+                Poll::Ready(Some(mut request)) => {
+                    request.body_timeout = self.body_timeout_state();
+                    return Poll::Ready(Some(request));
+                }
+                Poll::Ready(None) => drop(self.requests.take()),
+                Poll::Pending => {}
+            }
+        }
         loop {
             match self.decoding.poll_next_unpin(cx) {
-                Poll::Ready(Some(Ok((transport, head)))) => {
+                Poll::Ready(Some((Ok((transport, head)), served))) => {
                     match BodyDecodeWithContinueState::from_head(&head) {
                         Ok(state) => {
                             return Poll::Ready(Some(HttpRequest {
                                 head,
                                 state,
                                 transport,
+                                keep_alive: self.keep_alive(served + 1),
+                                body_timeout: self.body_timeout_state(),
+                                h2: None,
                             }))
                         }
                         Err(err) => log::debug!("http head error: {:?}", err),
                     };
                 }
-                Poll::Ready(Some(Err(err))) => log::debug!("http head decode error: {:?}", err),
-                Poll::Ready(None) | Poll::Pending => match &mut self.incoming {
-                    Some(incoming) => match incoming.poll_next_unpin(cx) {
-                        Poll::Ready(Some(transport)) => {
-                            self.decoding.push(RequestHead::decode(transport))
+                Poll::Ready(Some((Err(err), _))) => {
+                    if err.kind() == io::ErrorKind::TimedOut {
+                        self.timeout_counter.increment();
+                    }
+                    log::debug!("http head decode error: {:?}", err)
+                }
+                Poll::Ready(None) | Poll::Pending => {
+                    match self.reuse_receiver.poll_next_unpin(cx) {
+                        Poll::Ready(Some((transport, served))) => {
+                            let mut decode =
+                                RequestHeadDecode::new(transport, served, self.timeouts);
+                            decode.shutdown = self.shutdown.clone();
+                            self.decoding.push(decode)
                         }
-                        Poll::Ready(None) => drop(self.incoming.take()),
-                        Poll::Pending => return Poll::Pending,
-                    },
-                    None => match self.is_terminated() {
-                        true => return Poll::Ready(None),
-                        false => return Poll::Pending,
-                    },
-                },
+                        Poll::Ready(None) | Poll::Pending if self.heads_exhausted() => {
+                            return Poll::Pending
+                        }
+                        Poll::Ready(None) | Poll::Pending => match &mut self.incoming {
+                            Some(incoming) => match incoming.poll_next_unpin(cx) {
+                                Poll::Ready(Some(transport)) => {
+                                    let decode =
+                                        RequestHeadDecode::new(transport, 0, self.timeouts);
+                                    self.decoding.push(decode)
+                                }
+                                Poll::Ready(None) => {
+                                    drop(self.incoming.take());
+                                    drop(self.reuse_sender.take());
+                                }
+                                Poll::Pending => return Poll::Pending,
+                            },
+                            None => match self.is_terminated() {
+                                true => return Poll::Ready(None),
+                                false => return Poll::Pending,
+                            },
+                        },
+                    }
+                }
             }
         }
     }
@@ -79,7 +260,10 @@ impl<IO: AsyncRead + AsyncWrite + Unpin, T: Stream<Item = IO> + Unpin> FusedStre
     for HttpIncoming<IO, T>
 {
     fn is_terminated(&self) -> bool {
-        self.incoming.is_none() && self.decoding.is_terminated()
+        self.incoming.is_none()
+            && self.decoding.is_terminated()
+            && self.reuse_receiver.is_terminated()
+            && self.requests.is_none()
     }
 }
 
@@ -88,10 +272,285 @@ impl<IO: AsyncRead + AsyncWrite + Unpin, T: Stream<Item = IO> + Unpin> Unpin
 {
 }
 
+#[derive(Copy, Clone, Debug, Default)]
+struct Timeouts {
+    head: Option<Duration>,
+    idle: Option<Duration>,
+    body: Option<Duration>,
+    respond: bool,
+}
+
+/// Shared counter of connections closed due to exceeding a timeout, see
+/// [HttpIncoming::timeout_counter].
+#[derive(Clone, Debug, Default)]
+pub struct TimeoutCounter(Arc<AtomicUsize>);
+
+impl TimeoutCounter {
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+    fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+struct RequestHeadDecode<IO: AsyncRead + AsyncWrite + Unpin> {
+    state: RequestHeadDecodeState<IO>,
+    served: usize,
+    timeouts: Timeouts,
+    timer: Option<Timer>,
+    started: bool,
+    shutdown: Option<ShutdownSignal>,
+}
+
+enum RequestHeadDecodeState<IO: AsyncRead + AsyncWrite + Unpin> {
+    Decoding(BufferDecodeState<RequestHead<'static>>, IO),
+    Responding(BufferWrite<IO>),
+    Closing(IO),
+    Done,
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> RequestHeadDecode<IO> {
+    fn new(transport: IO, served: usize, timeouts: Timeouts) -> Self {
+        let state = BufferDecodeState::new(8192, 128, &request_head_parse);
+        let timeout = match served {
+            0 => timeouts.head,
+            _ => timeouts.idle,
+        };
+        Self {
+            state: RequestHeadDecodeState::Decoding(state, transport),
+            served,
+            timeouts,
+            timer: timeout.map(Timer::after),
+            started: false,
+            shutdown: None,
+        }
+    }
+    fn take_transport(&mut self) -> IO {
+        match std::mem::replace(&mut self.state, RequestHeadDecodeState::Done) {
+            RequestHeadDecodeState::Decoding(_, transport) => transport,
+            _ => unreachable!(),
+        }
+    }
+    /// Only set for persistent connections awaiting a further request.
+    fn is_shutting_down(&mut self, cx: &mut Context<'_>) -> bool {
+        match &mut self.shutdown {
+            Some(shutdown) => shutdown.poll_state(cx) != ShutdownState::Running,
+            None => false,
+        }
+    }
+    fn poll_decode(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<(IO, RequestHead<'static>)>> {
+        let (state, transport) = match &mut self.state {
+            RequestHeadDecodeState::Decoding(state, transport) => (state, transport),
+            _ => unreachable!(),
+        };
+        let started = self.started;
+        let mut observed = ReadObserver {
+            transport,
+            started: &mut self.started,
+        };
+        let p = state.poll(cx, &mut observed);
+        if !started && self.started && self.served > 0 {
+            self.timer = self.timeouts.head.map(Timer::after);
+        }
+        match p {
+            Poll::Ready(result) => {
+                let transport = self.take_transport();
+                Poll::Ready(result.map(|head| (transport, head)))
+            }
+            Poll::Pending if !self.started && self.is_shutting_down(cx) => {
+                drop(self.take_transport());
+                Poll::Ready(Err(io::ErrorKind::ConnectionAborted.into()))
+            }
+            Poll::Pending => match &mut self.timer {
+                Some(timer) => match timer.poll_unpin(cx) {
+                    Poll::Ready(_) => {
+                        let transport = self.take_transport();
+                        if self.started && self.timeouts.respond {
+                            self.timer = self.timeouts.head.map(Timer::after);
+                            self.state = RequestHeadDecodeState::Responding(
+                                request_timeout_response().encode(transport),
+                            );
+                            // The caller continues polling in the new state.
+                            return Poll::Pending;
+                        }
+                        Poll::Ready(Err(io::ErrorKind::TimedOut.into()))
+                    }
+                    Poll::Pending => Poll::Pending,
+                },
+                None => Poll::Pending,
+            },
+        }
+    }
+}
+
+/// Like the [RequestHead] decoder, but also accepting HTTP/1.0 requests.
+fn request_head_parse(buffer: &[u8], max_headers: usize) -> io::Result<RequestHead<'static>> {
+    let invalid_data = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
+    let mut parsed = httparse::Request::new(&mut headers);
+    if parsed
+        .parse(buffer)
+        .map_err(|err| invalid_data(&err.to_string()))?
+        .is_partial()
+    {
+        return Err(invalid_data("malformed HTTP head"));
+    }
+    let version = match parsed.version {
+        Some(0) => Version::HTTP_10,
+        Some(1) => Version::HTTP_11,
+        _ => return Err(invalid_data("unsupported HTTP version")),
+    };
+    let method = Method::from_bytes(parsed.method.unwrap_or("").as_bytes())
+        .map_err(|_| invalid_data("invalid method"))?;
+    let uri = parsed
+        .path
+        .unwrap_or("")
+        .parse::<Uri>()
+        .map_err(|_| invalid_data("invalid uri"))?;
+    let mut header_map = HeaderMap::with_capacity(parsed.headers.len());
+    for header in parsed.headers.iter() {
+        header_map.append(
+            HeaderName::from_bytes(header.name.as_bytes())
+                .map_err(|_| invalid_data("invalid header name"))?,
+            HeaderValue::from_bytes(header.value)
+                .map_err(|_| invalid_data("invalid header value"))?,
+        );
+    }
+    Ok(RequestHead::new(
+        method,
+        Cow::Owned(uri),
+        version,
+        Cow::Owned(header_map),
+    ))
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> Future for RequestHeadDecode<IO> {
+    type Output = (io::Result<(IO, RequestHead<'static>)>, usize);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let served = self.served;
+        loop {
+            let this = &mut *self;
+            match &mut this.state {
+                RequestHeadDecodeState::Decoding(..) => match this.poll_decode(cx) {
+                    Poll::Ready(result) => return Poll::Ready((result, served)),
+                    Poll::Pending => match this.state {
+                        RequestHeadDecodeState::Responding(_) => continue,
+                        _ => return Poll::Pending,
+                    },
+                },
+                RequestHeadDecodeState::Responding(response) => match response.poll_unpin(cx) {
+                    Poll::Ready(Ok(transport)) => {
+                        this.state = RequestHeadDecodeState::Closing(transport);
+                        continue;
+                    }
+                    Poll::Ready(Err(err)) => log::debug!("error sending 408: {:?}", err),
+                    Poll::Pending => match this.timer.as_mut().map(|t| t.poll_unpin(cx)) {
+                        Some(Poll::Ready(_)) => {}
+                        Some(Poll::Pending) | None => return Poll::Pending,
+                    },
+                },
+                RequestHeadDecodeState::Closing(transport) => {
+                    match Pin::new(transport).poll_close(cx) {
+                        Poll::Ready(Ok(())) => {}
+                        Poll::Ready(Err(err)) => log::debug!("error sending 408: {:?}", err),
+                        Poll::Pending => match this.timer.as_mut().map(|t| t.poll_unpin(cx)) {
+                            Some(Poll::Ready(_)) => {}
+                            Some(Poll::Pending) | None => return Poll::Pending,
+                        },
+                    }
+                }
+                RequestHeadDecodeState::Done => unreachable!(),
+            }
+            this.state = RequestHeadDecodeState::Done;
+            return Poll::Ready((Err(io::ErrorKind::TimedOut.into()), served));
+        }
+    }
+}
+
+/// Transport wrapper recording whether any data has been received.
+struct ReadObserver<'a, IO> {
+    transport: &'a mut IO,
+    started: &'a mut bool,
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for ReadObserver<'_, IO> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let p = Pin::new(&mut *self.transport).poll_read(cx, buf);
+        if let Poll::Ready(Ok(1..)) = p {
+            *self.started = true;
+        }
+        p
+    }
+}
+
+fn request_timeout_response() -> ResponseHead<'static> {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONNECTION, HeaderValue::from_static("close"));
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(0));
+    ResponseHead::new(
+        StatusCode::REQUEST_TIMEOUT,
+        Version::HTTP_11,
+        Cow::Owned(headers),
+    )
+}
+
+pub(crate) struct BodyTimeout {
+    duration: Duration,
+    timer: Option<Timer>,
+    expired: bool,
+    complete: bool,
+    respond: bool,
+    counter: TimeoutCounter,
+}
+
+/// Handle for returning a persistent connection to the [HttpIncoming] it originated from.
+pub(crate) struct KeepAlive<IO> {
+    sender: UnboundedSender<(IO, usize)>,
+    served: usize,
+    last: bool,
+    shutdown: Option<ShutdownSignal>,
+}
+
+impl<IO> KeepAlive<IO> {
+    fn permitted(&self) -> bool {
+        let shutting_down = match &self.shutdown {
+            Some(shutdown) => shutdown.is_triggered(),
+            None => false,
+        };
+        !self.last && !shutting_down && !self.sender.is_closed()
+    }
+    fn reuse(self, transport: IO) -> Result<(), IO> {
+        self.sender
+            .unbounded_send((transport, self.served))
+            .map_err(|err| err.into_inner().0)
+    }
+}
+
+fn connection_has_token(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
 pub struct HttpRequest<IO: AsyncRead + AsyncWrite + Unpin = TcpOrTlsStream> {
     pub(crate) head: RequestHead<'static>,
     pub(crate) state: BodyDecodeWithContinueState,
     pub(crate) transport: IO,
+    pub(crate) keep_alive: Option<KeepAlive<IO>>,
+    pub(crate) body_timeout: Option<BodyTimeout>,
+    pub(crate) h2: Option<Http2Exchange>,
 }
 
 impl core::fmt::Debug for HttpRequest {
@@ -108,6 +567,40 @@ impl<IO: AsyncRead + AsyncWrite + Unpin + IsTls> IsTls for HttpRequest<IO> {
     }
 }
 
+impl<IO: AsyncRead + AsyncWrite + Unpin + PeerAddr> PeerAddr for HttpRequest<IO> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.transport.peer_addr()
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.transport.local_addr()
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin + HasTlsInfo> HasTlsInfo for HttpRequest<IO> {
+    fn tls_info(&self) -> Option<TlsInfo> {
+        self.transport.tls_info()
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin + ClientCertificate> ClientCertificate for HttpRequest<IO> {
+    fn peer_certificates(&self) -> Option<&[CertificateDer<'_>]> {
+        self.transport.peer_certificates()
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin + ProxyInfo> ProxyInfo for HttpRequest<IO> {
+    fn proxy_header(&self) -> Option<&ProxyHeader> {
+        self.transport.proxy_header()
+    }
+}
+
+#[cfg(unix)]
+impl<IO: AsyncRead + AsyncWrite + Unpin + PeerCred> PeerCred for HttpRequest<IO> {
+    fn peer_cred(&self) -> io::Result<PeerCredentials> {
+        self.transport.peer_cred()
+    }
+}
+
 impl<IO: AsyncRead + AsyncWrite + Unpin> HttpRequest<IO> {
     /// Direct access to the request as [http::Request] with body decoder and underlying transport.
     /// The transport may be extracted using
@@ -119,14 +612,18 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> HttpRequest<IO> {
     /// ```
     /// Doing this after starting to read the body may lead to one of these situations:
     /// - The meaning of remaining data read from the transport is ambiguous, if the body has been
-    /// partially consumed and is encoded using
-    /// [chunked transfer encoding](https://en.wikipedia.org/wiki/Chunked_transfer_encoding).
+    ///   partially consumed and is encoded using
+    ///   [chunked transfer encoding](https://en.wikipedia.org/wiki/Chunked_transfer_encoding).
     /// - If the request includes the `Expect: 100-continue` header and if the body reader has been
-    /// polled, but did not yield any data yet, the informational response `100 Continue` may have
-    /// been fully, partially or not sent on the transport.
+    ///   polled, but did not yield any data yet, the informational response `100 Continue` may have
+    ///   been fully, partially or not sent on the transport.
     ///
     /// Reasoning about the protocol state is only trivial before calling [Self::body()] and after
     /// consuming the whole body.
+    ///
+    /// The connection will not be kept alive for further requests, even if the request is later
+    /// restored using [Self::from_inner()]. For requests received over HTTP/2 the body is empty
+    /// and the stream is reset, as it cannot be accessed as a transport.
     pub fn into_inner(self) -> Request<BodyDecodeWithContinue<BodyDecodeWithContinueState, IO>> {
         Request::from_parts(self.head.into(), self.state.into_async_read(self.transport))
     }
@@ -141,15 +638,40 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> HttpRequest<IO> {
             head,
             state,
             transport,
+            keep_alive: None,
+            body_timeout: None,
+            h2: None,
         }
     }
     /// Move on to responding after consuming and discarding the remaining request body data.
     pub async fn response(mut self) -> io::Result<HttpResponse<IO>> {
-        while 0 < self.body().read(&mut [0u8; 1 << 14]).await? {}
+        if let Err(err) = self.discard_body().await {
+            if let Some(body_timeout) = &self.body_timeout {
+                if err.kind() == io::ErrorKind::TimedOut && body_timeout.respond {
+                    if let Some(exchange) = &mut self.h2 {
+                        exchange.send_timeout_response();
+                        return Err(err);
+                    }
+                    let timer = Timer::after(body_timeout.duration);
+                    let transport = &mut self.transport;
+                    let respond = async move {
+                        request_timeout_response().encode(&mut *transport).await?;
+                        transport.close().await
+                    };
+                    if let Either::Left((Err(err), _)) = select(Box::pin(respond), timer).await {
+                        debug!("error sending 408: {:?}", err)
+                    }
+                }
+            }
+            return Err(err);
+        }
         let Self {
             head,
             state: _,
             transport,
+            keep_alive,
+            body_timeout: _,
+            h2,
         } = self;
         let request_head = http::request::Parts::from(head);
         let request_headers = request_head.headers;
@@ -162,14 +684,22 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> HttpRequest<IO> {
             request_method,
             head: ResponseHead::new(StatusCode::OK, request_head.version, headers),
             transport,
+            keep_alive,
+            h2,
         })
     }
 
+    async fn discard_body(&mut self) -> io::Result<()> {
+        while 0 < self.body().read(&mut [0u8; 1 << 14]).await? {}
+        Ok(())
+    }
     /// Access the request body data stream as [futures::io::AsyncRead].
     /// If the request includes the `Expect: 100-continue` the informational response `100 Continue`
     /// will be sent to the client before the reader emits any body data.
-    pub fn body(&mut self) -> BodyDecodeWithContinue<&mut BodyDecodeWithContinueState, &mut IO> {
-        self.state.as_async_read(&mut self.transport)
+    /// Reading fails with [io::ErrorKind::TimedOut] if the body is not received within the limit
+    /// set by [HttpIncoming::body_timeout].
+    pub fn body(&mut self) -> HttpRequestBody<'_, IO> {
+        HttpRequestBody { request: self }
     }
     /// Read whole body as [String]. See [Self::body] for details.
     pub async fn body_string(&mut self, limit: usize) -> io::Result<String> {
@@ -207,7 +737,7 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> HttpRequest<IO> {
     }
     /// Access the URI as [http::Uri].
     pub fn uri(&self) -> &Uri {
-        &self.head.uri()
+        self.head.uri()
     }
     /// Return the method as [http::Method].
     pub fn method(&self) -> Method {
@@ -219,12 +749,67 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> HttpRequest<IO> {
     }
 }
 
+/// Request body decoder returned by [HttpRequest::body].
+pub struct HttpRequestBody<'a, IO: AsyncRead + AsyncWrite + Unpin = TcpOrTlsStream> {
+    request: &'a mut HttpRequest<IO>,
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncRead for HttpRequestBody<'_, IO> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let HttpRequest {
+            state,
+            transport,
+            body_timeout,
+            h2,
+            ..
+        } = &mut *self.request;
+        let mut poll_read = |cx: &mut Context<'_>, buf: &mut [u8]| match h2 {
+            Some(exchange) => exchange.poll_read(cx, buf),
+            None => state.poll_read(cx, buf, transport),
+        };
+        let body_timeout = match body_timeout {
+            Some(body_timeout) if !body_timeout.complete => body_timeout,
+            _ => return poll_read(cx, buf),
+        };
+        let duration = body_timeout.duration;
+        body_timeout
+            .timer
+            .get_or_insert_with(|| Timer::after(duration));
+        match poll_read(cx, buf) {
+            Poll::Ready(Ok(0)) if !buf.is_empty() => {
+                // The body is complete, so there is nothing left to time out.
+                body_timeout.complete = true;
+                body_timeout.timer = None;
+                Poll::Ready(Ok(0))
+            }
+            Poll::Pending => {
+                let timer = body_timeout.timer.as_mut().unwrap();
+                if timer.poll_unpin(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                if !body_timeout.expired {
+                    body_timeout.expired = true;
+                    body_timeout.counter.increment();
+                }
+                Poll::Ready(Err(io::ErrorKind::TimedOut.into()))
+            }
+            p => p,
+        }
+    }
+}
+
 pub struct HttpResponse<IO: AsyncRead + AsyncWrite + Unpin = TcpOrTlsStream> {
     request_uri: Uri,
     request_headers: HeaderMap,
     request_method: Method,
     head: ResponseHead<'static>,
     transport: IO,
+    keep_alive: Option<KeepAlive<IO>>,
+    h2: Option<Http2Exchange>,
 }
 
 impl core::fmt::Debug for HttpResponse {
@@ -235,6 +820,42 @@ impl core::fmt::Debug for HttpResponse {
     }
 }
 
+impl<IO: AsyncRead + AsyncWrite + Unpin + PeerAddr> PeerAddr for HttpResponse<IO> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.transport.peer_addr()
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.transport.local_addr()
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin + HasTlsInfo> HasTlsInfo for HttpResponse<IO> {
+    fn tls_info(&self) -> Option<TlsInfo> {
+        self.transport.tls_info()
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin + ClientCertificate> ClientCertificate
+    for HttpResponse<IO>
+{
+    fn peer_certificates(&self) -> Option<&[CertificateDer<'_>]> {
+        self.transport.peer_certificates()
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin + ProxyInfo> ProxyInfo for HttpResponse<IO> {
+    fn proxy_header(&self) -> Option<&ProxyHeader> {
+        self.transport.proxy_header()
+    }
+}
+
+#[cfg(unix)]
+impl<IO: AsyncRead + AsyncWrite + Unpin + PeerCred> PeerCred for HttpResponse<IO> {
+    fn peer_cred(&self) -> io::Result<PeerCredentials> {
+        self.transport.peer_cred()
+    }
+}
+
 impl<IO: AsyncRead + AsyncWrite + Unpin> HttpResponse<IO> {
     /// Access the original requests headers as [http::HeaderMap].
     pub fn request_headers(&self) -> &HeaderMap {
@@ -280,23 +901,269 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> HttpResponse<IO> {
     }
     /// Send the request with the specified body. See [Self::body] for sending a response with a
     /// streaming body.
+    ///
+    /// The body is omitted if the request method is `HEAD` or the status is `1xx`, `204` or `304`,
+    /// while the headers are sent as if it was not. This also applies to [Self::body] and
+    /// [Self::body_with_length], which discard any data written in this case.
     pub async fn send(self, body: impl AsRef<[u8]>) -> io::Result<()> {
-        let mut encoder = self.body().await?;
-        encoder.write_all(body.as_ref()).await?;
+        let body = body.as_ref();
+        let mut encoder = self.body_with_length(body.len() as u64).await?;
+        encoder.write_all(body).await?;
         encoder.close().await?;
         Ok(())
     }
-    /// Move on to sending body after sending response head.
-    pub async fn body(mut self) -> io::Result<BodyEncode<IO>> {
-        self.insert_header(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+    /// Move on to sending a streaming body of unknown length after sending response head.
+    /// The body uses chunked transfer encoding, except for HTTP/1.0 clients which do not support
+    /// it and instead receive a body delimited by closing the connection, and HTTP/2 clients which
+    /// receive it as data frames.
+    ///
+    /// Closing the returned body encoder completes the response. Unless the connection is closed
+    /// (see [HttpIncoming::max_requests_per_connection]), the transport is subsequently handed
+    /// back to the [HttpIncoming] to await the next request.
+    pub async fn body(mut self) -> io::Result<HttpResponseBody<IO>> {
+        let framing = match self.head.version() {
+            Version::HTTP_11 => {
+                self.insert_header(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+                BodyFraming::Chunked
+            }
+            _ => BodyFraming::Close,
+        };
+        self.start_body(framing).await
+    }
+    /// Move on to sending a body of the specified length after sending response head with a
+    /// `Content-Length` header. Writing more data than declared fails with
+    /// [io::ErrorKind::InvalidData], as does closing the encoder before writing all of it.
+    /// See [Self::body] for details on completing the response.
+    pub async fn body_with_length(mut self, length: u64) -> io::Result<HttpResponseBody<IO>> {
+        self.headers_mut().remove(TRANSFER_ENCODING);
+        self.insert_header(CONTENT_LENGTH, HeaderValue::from(length));
+        self.start_body(BodyFraming::Length(length)).await
+    }
+    async fn start_body(mut self, framing: BodyFraming) -> io::Result<HttpResponseBody<IO>> {
+        let status = self.status();
+        if status.is_informational() || status == StatusCode::NO_CONTENT {
+            self.headers_mut().remove(CONTENT_LENGTH);
+            self.headers_mut().remove(TRANSFER_ENCODING);
+        }
+        let framing = match self.has_body() {
+            true => framing,
+            false => BodyFraming::Suppressed,
+        };
+        if let Some(exchange) = &mut self.h2 {
+            let send = exchange.send_response(&self.head, framing == BodyFraming::Suppressed)?;
+            return Ok(HttpResponseBody::http2(send, framing));
+        }
+        let keep_alive = self.negotiate_keep_alive(framing);
         self.head.encode(&mut self.transport).await?;
-        Ok(BodyEncode::new(self.transport, None))
+        Ok(HttpResponseBody::new(self.transport, framing, keep_alive))
+    }
+    /// Responses to `HEAD` requests and responses with status `1xx`, `204` or `304` have no body.
+    fn has_body(&self) -> bool {
+        let status = self.status();
+        self.request_method != Method::HEAD
+            && !status.is_informational()
+            && status != StatusCode::NO_CONTENT
+            && status != StatusCode::NOT_MODIFIED
+    }
+    /// Decide whether the connection persists after this response and set the `Connection` header
+    /// accordingly. Persistence requires a self-delimiting body.
+    fn negotiate_keep_alive(&mut self, framing: BodyFraming) -> Option<KeepAlive<IO>> {
+        let version = self.head.version();
+        let requested = match version {
+            Version::HTTP_11 => !connection_has_token(&self.request_headers, "close"),
+            Version::HTTP_10 => connection_has_token(&self.request_headers, "keep-alive"),
+            _ => false,
+        };
+        let possible =
+            !connection_has_token(self.headers(), "close") && framing != BodyFraming::Close;
+        match self.keep_alive.take() {
+            Some(keep_alive) if requested && possible && keep_alive.permitted() => {
+                if version == Version::HTTP_10 {
+                    self.insert_header(CONNECTION, HeaderValue::from_static("keep-alive"));
+                }
+                Some(keep_alive)
+            }
+            _ => {
+                if version == Version::HTTP_11 && !connection_has_token(self.headers(), "close") {
+                    self.insert_header(CONNECTION, HeaderValue::from_static("close"));
+                }
+                None
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum BodyFraming {
+    Chunked,
+    Length(u64),
+    Close,
+    Suppressed,
+}
+
+/// Response body encoder returned by [HttpResponse::body] and [HttpResponse::body_with_length].
+/// The response is complete once the encoder has been closed.
+pub struct HttpResponseBody<IO: AsyncRead + AsyncWrite + Unpin = TcpOrTlsStream> {
+    encode: Option<BodySink<IO>>,
+    remaining: Option<u64>,
+    discard: bool,
+    keep_alive: Option<KeepAlive<IO>>,
+    closing: Option<IO>,
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> HttpResponseBody<IO> {
+    fn new(transport: IO, framing: BodyFraming, keep_alive: Option<KeepAlive<IO>>) -> Self {
+        let (length, remaining) = match framing {
+            BodyFraming::Chunked => (None, None),
+            BodyFraming::Length(length) => (Some(length), Some(length)),
+            // A fixed length encoder without practical limit passes data through unmodified.
+            BodyFraming::Close => (Some(u64::MAX), None),
+            BodyFraming::Suppressed => (Some(0), None),
+        };
+        Self {
+            encode: Some(BodySink::Http1(BodyEncode::new(
+                KeepOpen(transport),
+                length,
+            ))),
+            remaining,
+            discard: framing == BodyFraming::Suppressed,
+            keep_alive,
+            closing: None,
+        }
+    }
+    fn http2(send: SendStream<Bytes>, framing: BodyFraming) -> Self {
+        let remaining = match framing {
+            BodyFraming::Length(length) => Some(length),
+            _ => None,
+        };
+        Self {
+            encode: Some(BodySink::Http2(send)),
+            remaining,
+            discard: framing == BodyFraming::Suppressed,
+            keep_alive: None,
+            closing: None,
+        }
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+enum BodySink<IO: AsyncWrite + Unpin> {
+    Http1(BodyEncode<KeepOpen<IO>>),
+    Http2(SendStream<Bytes>),
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncWrite for HttpResponseBody<IO> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.remaining == Some(0) && !buf.is_empty() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "body exceeds declared length",
+            )));
+        }
+        let discard = self.discard;
+        let remaining = self.remaining;
+        let p = match &mut self.encode {
+            Some(_) if discard => Poll::Ready(Ok(buf.len())),
+            Some(BodySink::Http1(encode)) => Pin::new(encode).poll_write(cx, buf),
+            Some(BodySink::Http2(send)) => {
+                let n = remaining.map_or(buf.len(), |r| (buf.len() as u64).min(r) as usize);
+                poll_send_data(send, cx, &buf[..n], false)
+            }
+            None => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        };
+        if let (Poll::Ready(Ok(n)), Some(remaining)) = (&p, &mut self.remaining) {
+            *remaining -= *n as u64;
+        }
+        p
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.encode {
+            Some(BodySink::Http1(encode)) => Pin::new(encode).poll_flush(cx),
+            Some(BodySink::Http2(_)) | None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(remaining @ 1..) = self.remaining {
+            if let Some(encode) = self.encode.take() {
+                if let BodySink::Http2(mut send) = encode {
+                    send.send_reset(Reason::INTERNAL_ERROR);
+                }
+                log::debug!(
+                    "response body is {} bytes short of declared length",
+                    remaining
+                );
+            }
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "body shorter than declared length",
+            )));
+        }
+        let this = &mut *self;
+        match &mut this.encode {
+            Some(BodySink::Http1(encode)) => {
+                ready!(Pin::new(encode).poll_close(cx))?;
+                if let Some(BodySink::Http1(encode)) = this.encode.take() {
+                    let (KeepOpen(transport), _) = encode.checkpoint();
+                    this.closing = match this.keep_alive.take() {
+                        Some(keep_alive) => keep_alive.reuse(transport).err(),
+                        None => Some(transport),
+                    };
+                }
+            }
+            Some(BodySink::Http2(send)) => {
+                // Without a body the stream already ended with the response head.
+                if !this.discard {
+                    send.send_data(Bytes::new(), true)
+                        .map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))?;
+                }
+                this.encode = None;
+            }
+            None => {}
+        }
+        match &mut self.closing {
+            Some(transport) => match Pin::new(transport).poll_close(cx) {
+                Poll::Ready(result) => {
+                    self.closing.take();
+                    Poll::Ready(result)
+                }
+                Poll::Pending => Poll::Pending,
+            },
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+/// Transport wrapper which only flushes on close, so the connection may be reused.
+struct KeepOpen<IO>(IO);
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for KeepOpen<IO> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
     }
 }
 
 impl HttpIncoming<TcpStream, TcpIncoming> {
     pub fn redirect_https(self) -> RedirectHttps {
         RedirectHttps {
+            shutdown: self.shutdown.clone(),
             incoming: self,
             redirecting: FuturesUnordered::new(),
         }
@@ -307,35 +1174,51 @@ pub struct RedirectHttps {
     incoming: HttpIncoming<TcpStream, TcpIncoming>,
     // TODO: replace Box<dyn ...> once https://github.com/rust-lang/rust/issues/63063 is stable
     redirecting: FuturesUnordered<Pin<Box<dyn Future<Output = ()> + Send + Sync>>>,
+    shutdown: Option<ShutdownSignal>,
 }
 
 impl RedirectHttps {
-    fn set_location_header(resp: &mut HttpResponse<TcpStream>) -> http::Result<()> {
-        let authority = match resp.request_headers().get(HOST) {
-            Some(host) => Some(Authority::try_from(host.as_bytes())?),
-            None => None,
-        };
-        let mut parts: Parts = Default::default();
-        parts.scheme = Some(Scheme::HTTPS);
-        parts.authority = authority;
-        parts.path_and_query = resp.uri().path_and_query().cloned();
-        let header_value = HeaderValue::try_from(Uri::from_parts(parts)?.to_string())?;
-        resp.insert_header(LOCATION, header_value);
-        Ok(())
+    /// Stop accepting connections once shutdown is triggered and complete after all pending
+    /// redirects have been sent or the deadline has passed, see [Shutdown](crate::Shutdown).
+    pub fn graceful_shutdown(mut self, signal: ShutdownSignal) -> Self {
+        self.incoming.incoming = self
+            .incoming
+            .incoming
+            .map(|incoming| incoming.graceful_shutdown(signal.clone()));
+        self.incoming.shutdown = Some(signal.clone());
+        self.shutdown = Some(signal);
+        self
     }
-    async fn send(req: HttpRequest<TcpStream>) {
-        match req.response().await {
-            Err(err) => debug!("error reading body of request to be redirected: {:?}", err),
-            Ok(mut resp) => match Self::set_location_header(&mut resp) {
-                Err(err) => debug!("error constructing redirect location header: {:?}", err),
-                Ok(()) => {
-                    resp.set_status(StatusCode::TEMPORARY_REDIRECT);
-                    if let Err(err) = resp.send(&[]).await {
-                        debug!("error sending redirect response: {:?}", err)
-                    }
+}
+
+fn set_https_location_header<IO: AsyncRead + AsyncWrite + Unpin>(
+    resp: &mut HttpResponse<IO>,
+) -> http::Result<()> {
+    let authority = match resp.request_headers().get(HOST) {
+        Some(host) => Some(Authority::try_from(host.as_bytes())?),
+        None => None,
+    };
+    let mut parts: Parts = Default::default();
+    parts.scheme = Some(Scheme::HTTPS);
+    parts.authority = authority;
+    parts.path_and_query = resp.uri().path_and_query().cloned();
+    let header_value = HeaderValue::try_from(Uri::from_parts(parts)?.to_string())?;
+    resp.insert_header(LOCATION, header_value);
+    Ok(())
+}
+
+async fn send_https_redirect<IO: AsyncRead + AsyncWrite + Unpin>(req: HttpRequest<IO>) {
+    match req.response().await {
+        Err(err) => debug!("error reading body of request to be redirected: {:?}", err),
+        Ok(mut resp) => match set_https_location_header(&mut resp) {
+            Err(err) => debug!("error constructing redirect location header: {:?}", err),
+            Ok(()) => {
+                resp.set_status(StatusCode::TEMPORARY_REDIRECT);
+                if let Err(err) = resp.send(&[]).await {
+                    debug!("error sending redirect response: {:?}", err)
                 }
-            },
-        }
+            }
+        },
     }
 }
 
@@ -343,13 +1226,18 @@ impl Future for RedirectHttps {
     type Output = ();
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(shutdown) = &mut self.shutdown {
+            if shutdown.poll_state(cx) == ShutdownState::Expired {
+                self.redirecting.clear();
+            }
+        }
         loop {
             if let Poll::Ready(Some(())) = Pin::new(&mut self.redirecting).poll_next(cx) {
                 continue;
             }
             if !self.incoming.is_terminated() {
                 if let Poll::Ready(Some(req)) = Pin::new(&mut self.incoming).poll_next(cx) {
-                    self.redirecting.push(Box::pin(Self::send(req)));
+                    self.redirecting.push(Box::pin(send_https_redirect(req)));
                     continue;
                 }
             }
@@ -360,3 +1248,480 @@ impl Future for RedirectHttps {
         }
     }
 }
+
+impl<
+        IO: AsyncRead + AsyncWrite + Unpin + IsTls + Send + Sync + 'static,
+        T: Stream<Item = IO> + Unpin,
+    > HttpIncoming<IO, T>
+{
+    /// Redirect requests received without TLS to HTTPS, yielding only requests received over
+    /// TLS. Useful when accepting both on the same port, see
+    /// [TcpIncoming::sniff_tls_with_config].
+    pub fn redirect_plaintext(self) -> RedirectPlaintext<IO, T> {
+        RedirectPlaintext {
+            shutdown: self.shutdown.clone(),
+            incoming: self,
+            redirecting: FuturesUnordered::new(),
+        }
+    }
+}
+
+pub struct RedirectPlaintext<
+    IO: AsyncRead + AsyncWrite + Unpin = TcpOrTlsStream,
+    T: Stream<Item = IO> + Unpin = TcpOrTlsIncoming,
+> {
+    incoming: HttpIncoming<IO, T>,
+    redirecting: FuturesUnordered<Pin<Box<dyn Future<Output = ()> + Send + Sync>>>,
+    shutdown: Option<ShutdownSignal>,
+}
+
+impl<
+        IO: AsyncRead + AsyncWrite + Unpin + IsTls + Send + Sync + 'static,
+        T: Stream<Item = IO> + Unpin,
+    > RedirectPlaintext<IO, T>
+{
+    pub fn or_ws(self) -> HttpOrWsIncoming<IO, Self> {
+        HttpOrWsIncoming::new(self)
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin, T: Stream<Item = IO> + Unpin> Unpin
+    for RedirectPlaintext<IO, T>
+{
+}
+
+impl<
+        IO: AsyncRead + AsyncWrite + Unpin + IsTls + Send + Sync + 'static,
+        T: Stream<Item = IO> + Unpin,
+    > Stream for RedirectPlaintext<IO, T>
+{
+    type Item = HttpRequest<IO>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(shutdown) = &mut self.shutdown {
+            if shutdown.poll_state(cx) == ShutdownState::Expired {
+                self.redirecting.clear();
+            }
+        }
+        loop {
+            if let Poll::Ready(Some(())) = self.redirecting.poll_next_unpin(cx) {
+                continue;
+            }
+            if !self.incoming.is_terminated() {
+                match self.incoming.poll_next_unpin(cx) {
+                    Poll::Ready(Some(req)) if req.is_tls() => return Poll::Ready(Some(req)),
+                    Poll::Ready(Some(req)) => {
+                        self.redirecting.push(Box::pin(send_https_redirect(req)));
+                        continue;
+                    }
+                    Poll::Ready(None) | Poll::Pending => {}
+                }
+            }
+            return match self.is_terminated() {
+                true => Poll::Ready(None),
+                false => Poll::Pending,
+            };
+        }
+    }
+}
+
+impl<
+        IO: AsyncRead + AsyncWrite + Unpin + IsTls + Send + Sync + 'static,
+        T: Stream<Item = IO> + Unpin,
+    > FusedStream for RedirectPlaintext<IO, T>
+{
+    fn is_terminated(&self) -> bool {
+        self.incoming.is_terminated() && self.redirecting.is_terminated()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::stream;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Mutex;
+
+    /// Transport replaying scripted input, which stalls or ends once the input is consumed.
+    struct MockTransport {
+        input: Vec<u8>,
+        pos: usize,
+        stall: bool,
+        output: Arc<Mutex<Vec<u8>>>,
+        closed: Arc<AtomicBool>,
+    }
+
+    impl AsyncRead for MockTransport {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let n = buf.len().min(self.input.len() - self.pos);
+            if n == 0 && self.stall {
+                return Poll::Pending;
+            }
+            buf[..n].copy_from_slice(&self.input[self.pos..self.pos + n]);
+            self.pos += n;
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    impl AsyncWrite for MockTransport {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.output.lock().unwrap().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.closed.store(true, Ordering::SeqCst);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    type MockIncoming = HttpIncoming<
+        MockTransport,
+        stream::Chain<
+            stream::Iter<std::vec::IntoIter<MockTransport>>,
+            stream::Pending<MockTransport>,
+        >,
+    >;
+
+    struct Connection {
+        output: Arc<Mutex<Vec<u8>>>,
+        closed: Arc<AtomicBool>,
+    }
+
+    impl Connection {
+        fn output(&self) -> String {
+            String::from_utf8(self.output.lock().unwrap().clone()).unwrap()
+        }
+        fn is_closed(&self) -> bool {
+            self.closed.load(Ordering::SeqCst)
+        }
+    }
+
+    /// Incoming stream with a single connection receiving `input`, stalling afterwards if `stall`.
+    fn incoming(input: &[u8], stall: bool) -> (MockIncoming, Connection) {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+        let transport = MockTransport {
+            input: input.to_vec(),
+            pos: 0,
+            stall,
+            output: output.clone(),
+            closed: closed.clone(),
+        };
+        let transports = stream::iter(vec![transport]).chain(stream::pending());
+        (HttpIncoming::new(transports), Connection { output, closed })
+    }
+
+    /// Next request, unless none is received before a short timeout.
+    async fn next(incoming: &mut MockIncoming) -> Option<HttpRequest<MockTransport>> {
+        let timer = Timer::after(Duration::from_millis(200));
+        match select(incoming.next(), timer).await {
+            Either::Left((request, _)) => request,
+            Either::Right(_) => None,
+        }
+    }
+
+    async fn respond(incoming: &mut MockIncoming, path: &str, body: &str) {
+        let request = next(incoming).await.unwrap();
+        assert_eq!(request.uri().path(), path);
+        request.response().await.unwrap().send(body).await.unwrap();
+    }
+
+    #[test]
+    fn pipelined_requests() {
+        let (mut incoming, connection) = incoming(
+            b"GET /a HTTP/1.1\r\nHost: x\r\n\r\nGET /b HTTP/1.1\r\nHost: x\r\n\r\n",
+            true,
+        );
+        block_on(async {
+            respond(&mut incoming, "/a", "first").await;
+            respond(&mut incoming, "/b", "second").await;
+            assert!(next(&mut incoming).await.is_none());
+        });
+        let output = connection.output();
+        assert_eq!(output.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+        assert!(!output.contains("connection: close"));
+        assert!(output.ends_with("\r\n\r\nsecond"));
+        assert!(!connection.is_closed());
+    }
+
+    #[test]
+    fn connection_close() {
+        let (mut incoming, connection) = incoming(
+            b"GET /a HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\nGET /b HTTP/1.1\r\n\r\n",
+            true,
+        );
+        block_on(async {
+            respond(&mut incoming, "/a", "first").await;
+            assert!(next(&mut incoming).await.is_none());
+        });
+        assert!(connection.output().contains("connection: close\r\n"));
+        assert!(connection.is_closed());
+    }
+
+    #[test]
+    fn http10_without_keep_alive() {
+        let (mut incoming, connection) =
+            incoming(b"GET /a HTTP/1.0\r\n\r\nGET /b HTTP/1.0\r\n\r\n", true);
+        block_on(async {
+            respond(&mut incoming, "/a", "first").await;
+            assert!(next(&mut incoming).await.is_none());
+        });
+        assert!(!connection.output().contains("keep-alive"));
+        assert!(connection.is_closed());
+    }
+
+    #[test]
+    fn http10_with_keep_alive() {
+        let (mut incoming, connection) = incoming(
+            b"GET /a HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /b HTTP/1.0\r\n\r\n",
+            true,
+        );
+        block_on(async {
+            respond(&mut incoming, "/a", "first").await;
+            respond(&mut incoming, "/b", "second").await;
+        });
+        assert!(connection.output().contains("connection: keep-alive\r\n"));
+        assert!(connection.is_closed());
+    }
+
+    #[test]
+    fn request_limit() {
+        let (incoming, connection) = incoming(
+            b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /c HTTP/1.1\r\n\r\n",
+            true,
+        );
+        let mut incoming = incoming.max_requests_per_connection(2);
+        block_on(async {
+            respond(&mut incoming, "/a", "first").await;
+            respond(&mut incoming, "/b", "second").await;
+            assert!(next(&mut incoming).await.is_none());
+        });
+        let output = connection.output();
+        assert_eq!(output.matches("connection: close").count(), 1);
+        let last = &output[output.rfind("HTTP/1.1").unwrap()..];
+        assert!(last.contains("connection: close\r\n"));
+        assert!(last.ends_with("second"));
+        assert!(connection.is_closed());
+    }
+
+    #[test]
+    fn content_length() {
+        let (mut incoming, connection) = incoming(b"GET / HTTP/1.1\r\n\r\n", true);
+        block_on(respond(&mut incoming, "/", "hello"));
+        let output = connection.output();
+        assert!(output.contains("content-length: 5\r\n"));
+        assert!(!output.contains("transfer-encoding"));
+        assert!(output.ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn body_shorter_than_length() {
+        let (mut incoming, connection) = incoming(b"GET / HTTP/1.1\r\n\r\n", true);
+        block_on(async {
+            let response = next(&mut incoming).await.unwrap().response().await.unwrap();
+            let mut body = response.body_with_length(5).await.unwrap();
+            body.write_all(b"hel").await.unwrap();
+            let err = body.close().await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(next(&mut incoming).await.is_none());
+        });
+        assert!(connection.output().ends_with("\r\n\r\nhel"));
+    }
+
+    #[test]
+    fn body_longer_than_length() {
+        let (mut incoming, connection) = incoming(b"GET / HTTP/1.1\r\n\r\n", true);
+        block_on(async {
+            let response = next(&mut incoming).await.unwrap().response().await.unwrap();
+            let mut body = response.body_with_length(3).await.unwrap();
+            let err = body.write_all(b"hello").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        });
+        assert!(connection.output().ends_with("\r\n\r\nhel"));
+    }
+
+    #[test]
+    fn chunked_body() {
+        let (mut incoming, connection) = incoming(b"GET / HTTP/1.1\r\n\r\n", true);
+        block_on(async {
+            let response = next(&mut incoming).await.unwrap().response().await.unwrap();
+            let mut body = response.body().await.unwrap();
+            body.write_all(b"hello").await.unwrap();
+            body.close().await.unwrap();
+        });
+        let output = connection.output();
+        assert!(output.contains("transfer-encoding: chunked\r\n"));
+        assert!(!output.contains("content-length"));
+        assert!(output.ends_with("\r\n\r\n5\r\nhello\r\n0\r\n\r\n"));
+        assert!(!connection.is_closed());
+    }
+
+    #[test]
+    fn close_delimited_body() {
+        let (mut incoming, connection) = incoming(
+            b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET / HTTP/1.0\r\n\r\n",
+            true,
+        );
+        block_on(async {
+            let response = next(&mut incoming).await.unwrap().response().await.unwrap();
+            let mut body = response.body().await.unwrap();
+            body.write_all(b"hello").await.unwrap();
+            body.close().await.unwrap();
+            assert!(next(&mut incoming).await.is_none());
+        });
+        let output = connection.output();
+        assert!(output.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(!output.contains("transfer-encoding"));
+        assert!(!output.contains("content-length"));
+        assert!(!output.contains("keep-alive"));
+        assert!(output.ends_with("\r\n\r\nhello"));
+        assert!(connection.is_closed());
+    }
+
+    /// Respond to a request with `method` with `status` and a body, followed by a second request
+    /// on the same connection, returning the first response.
+    fn suppressed_body(method: &str, status: StatusCode) -> String {
+        let input = format!("{} /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n", method);
+        let (mut incoming, connection) = incoming(input.as_bytes(), true);
+        block_on(async {
+            let request = next(&mut incoming).await.unwrap();
+            let mut response = request.response().await.unwrap();
+            response.set_status(status);
+            response.send("hello").await.unwrap();
+            respond(&mut incoming, "/b", "second").await;
+        });
+        let output = connection.output();
+        let (first, second) = output.split_at(output.rfind("HTTP/1.1 200 OK").unwrap());
+        assert!(second.ends_with("\r\n\r\nsecond"));
+        assert!(!connection.is_closed());
+        assert!(first.ends_with("\r\n\r\n"));
+        assert!(!first.contains("hello"));
+        first.to_string()
+    }
+
+    #[test]
+    fn head_response() {
+        let response = suppressed_body("HEAD", StatusCode::OK);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("content-length: 5\r\n"));
+    }
+
+    #[test]
+    fn informational_response() {
+        let response = suppressed_body("GET", StatusCode::from_u16(103).unwrap());
+        assert!(response.starts_with("HTTP/1.1 103 Early Hints\r\n"));
+        assert!(!response.contains("content-length"));
+    }
+
+    #[test]
+    fn no_content_response() {
+        let response = suppressed_body("GET", StatusCode::NO_CONTENT);
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(!response.contains("content-length"));
+    }
+
+    #[test]
+    fn not_modified_response() {
+        let response = suppressed_body("GET", StatusCode::NOT_MODIFIED);
+        assert!(response.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+        assert!(response.contains("content-length: 5\r\n"));
+    }
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn head_timeout() {
+        let (incoming, connection) = incoming(b"GET /a HTTP/1.1\r\n", true);
+        let mut incoming = incoming.head_timeout(TIMEOUT);
+        let counter = incoming.timeout_counter();
+        block_on(async { assert!(next(&mut incoming).await.is_none()) });
+        assert_eq!(counter.get(), 1);
+        assert_eq!(connection.output(), "");
+    }
+
+    #[test]
+    fn head_timeout_response() {
+        let (incoming, connection) = incoming(b"GET /a HTTP/1.1\r\n", true);
+        let mut incoming = incoming
+            .head_timeout(TIMEOUT)
+            .request_timeout_response(true);
+        let counter = incoming.timeout_counter();
+        block_on(async { assert!(next(&mut incoming).await.is_none()) });
+        assert_eq!(counter.get(), 1);
+        let output = connection.output();
+        assert!(output.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert!(output.contains("connection: close\r\n"));
+        assert!(connection.is_closed());
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let (incoming, connection) = incoming(b"GET /a HTTP/1.1\r\n\r\n", true);
+        let mut incoming = incoming
+            .idle_timeout(TIMEOUT)
+            .request_timeout_response(true);
+        let counter = incoming.timeout_counter();
+        block_on(async {
+            respond(&mut incoming, "/a", "first").await;
+            assert!(next(&mut incoming).await.is_none());
+        });
+        assert_eq!(counter.get(), 1);
+        let output = connection.output();
+        assert!(output.ends_with("\r\n\r\nfirst"));
+        assert!(!output.contains("408"));
+    }
+
+    #[test]
+    fn body_timeout() {
+        let (incoming, connection) =
+            incoming(b"POST /a HTTP/1.1\r\ncontent-length: 10\r\n\r\nabc", true);
+        let mut incoming = incoming
+            .body_timeout(TIMEOUT)
+            .request_timeout_response(true);
+        let counter = incoming.timeout_counter();
+        block_on(async {
+            let request = next(&mut incoming).await.unwrap();
+            match request.response().await {
+                Err(err) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+                Ok(_) => panic!("body should time out"),
+            }
+        });
+        assert_eq!(counter.get(), 1);
+        let output = connection.output();
+        assert!(output.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert!(connection.is_closed());
+    }
+
+    #[test]
+    fn body_timeout_disarmed_once_complete() {
+        let (incoming, connection) =
+            incoming(b"POST /a HTTP/1.1\r\ncontent-length: 3\r\n\r\nabc", true);
+        let mut incoming = incoming
+            .body_timeout(TIMEOUT)
+            .request_timeout_response(true);
+        let counter = incoming.timeout_counter();
+        block_on(async {
+            let mut request = next(&mut incoming).await.unwrap();
+            assert_eq!(request.body_string(10).await.unwrap(), "abc");
+            Timer::after(TIMEOUT * 2).await;
+            let response = request.response().await.unwrap();
+            response.send("done").await.unwrap();
+        });
+        assert_eq!(counter.get(), 0);
+        let output = connection.output();
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(output.ends_with("\r\n\r\ndone"));
+    }
+}
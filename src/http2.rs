@@ -0,0 +1,733 @@
+use crate::shutdown::ShutdownState;
+use crate::{
+    ClientCertificate, HasTlsInfo, HttpIncoming, HttpRequest, IsTls, PeerAddr, ShutdownSignal,
+    TlsInfo, TlsStream,
+};
+use async_http_codec::{BodyDecodeWithContinueState, RequestHead, ResponseHead};
+use bytes::Bytes;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::prelude::*;
+use futures::ready;
+use futures::stream::{FusedStream, SelectAll};
+use h2::server::{Builder, Connection, Handshake, SendResponse};
+use h2::{RecvStream, SendStream};
+use http::header::{HeaderName, HOST};
+use http::{HeaderValue, Method, Request, Response, StatusCode, Uri, Version};
+use rustls_acme::futures_rustls::pki_types::CertificateDer;
+use std::borrow::Cow;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt};
+
+/// Serves HTTP/2 connections, see [TlsIncoming::http2](crate::TlsIncoming::http2) and
+/// [TcpIncoming::h2c](crate::TcpIncoming::h2c). Using [Self::http], HTTP/2 requests are yielded by
+/// the [HttpIncoming] alongside HTTP/1 requests, so existing request handlers work unchanged.
+///
+/// HTTP/2 requests have the version [Version::HTTP_2], the target in origin form and the
+/// `:authority` pseudo-header as `Host` header. Connection-specific response headers such as
+/// `Connection` and `Transfer-Encoding` are removed. WebSocket upgrades are not supported over
+/// HTTP/2.
+///
+/// Each connection is driven independently of accepting further connections, but only while the
+/// [HttpIncoming] is polled, so requests must be handled concurrently (e.g. spawned).
+///
+/// ```no_run
+/// # use async_web_server::{parse_pem, TcpIncoming};
+/// # let (cert_chain, key_der) = parse_pem(std::fs::read("cert.pem")?)?;
+/// let incoming = TcpIncoming::bind(([0, 0, 0, 0], 443))?
+///     .tls(cert_chain, key_der)
+///     .unwrap()
+///     .http2()
+///     .max_concurrent_streams(100)
+///     .http();
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct Http2Incoming<IO: AsyncRead + AsyncWrite + Unpin, T: Stream<Item = IO> + Unpin> {
+    incoming: Option<T>,
+    connections: SelectAll<Http2Connection<IO>>,
+    handoff: Option<UnboundedSender<Http2Connection<IO>>>,
+    detect: fn(&IO) -> bool,
+    builder: Builder,
+    shutdown: Option<ShutdownSignal>,
+}
+
+impl<
+        IO: AsyncRead + AsyncWrite + Unpin + PeerAddr + HasTlsInfo + ClientCertificate,
+        T: Stream<Item = IO> + Unpin,
+    > Http2Incoming<IO, T>
+{
+    /// Serve HTTP/2 on all connections, e.g. prior knowledge h2c or connections that already
+    /// negotiated `h2` via ALPN.
+    pub fn new(incoming: T) -> Self {
+        Self::with_detect(incoming, |_| true)
+    }
+    fn with_detect(incoming: T, detect: fn(&IO) -> bool) -> Self {
+        Http2Incoming {
+            incoming: Some(incoming),
+            connections: SelectAll::new(),
+            handoff: None,
+            detect,
+            builder: Builder::new(),
+            shutdown: None,
+        }
+    }
+    pub(crate) fn with_optional_shutdown(mut self, signal: Option<ShutdownSignal>) -> Self {
+        self.shutdown = signal;
+        self
+    }
+    /// Stop accepting connections once shutdown is triggered and send `GOAWAY` on open
+    /// connections, letting streams in progress complete until the deadline, see
+    /// [Shutdown](crate::Shutdown).
+    pub fn graceful_shutdown(mut self, signal: ShutdownSignal) -> Self {
+        self.shutdown = Some(signal);
+        self
+    }
+    /// Limit the number of concurrent streams per connection (unlimited by default).
+    pub fn max_concurrent_streams(mut self, max: u32) -> Self {
+        self.builder.max_concurrent_streams(max);
+        self
+    }
+    /// Flow control window for receiving data on each stream (65535 bytes by default).
+    pub fn initial_window_size(mut self, size: u32) -> Self {
+        self.builder.initial_window_size(size);
+        self
+    }
+    /// Flow control window for receiving data on each connection (65535 bytes by default).
+    pub fn initial_connection_window_size(mut self, size: u32) -> Self {
+        self.builder.initial_connection_window_size(size);
+        self
+    }
+    /// Largest frame payload accepted from clients (16384 bytes by default).
+    pub fn max_frame_size(mut self, size: u32) -> Self {
+        self.builder.max_frame_size(size);
+        self
+    }
+    /// Largest header list accepted from clients (16 MiB by default).
+    pub fn max_header_list_size(mut self, size: u32) -> Self {
+        self.builder.max_header_list_size(size);
+        self
+    }
+    /// Limit the response data buffered per stream while waiting for the client to grant flow
+    /// control capacity (400 KiB by default).
+    pub fn max_send_buffer_size(mut self, size: usize) -> Self {
+        self.builder.max_send_buffer_size(size);
+        self
+    }
+    /// Serve HTTP/1 and HTTP/2 requests. HTTP/2 connections are handed over to the
+    /// [HttpIncoming] when accepted, so this stream only yields HTTP/1 connections.
+    pub fn http(mut self) -> HttpIncoming<Http1OrHttp2Stream<IO>, Self>
+    where
+        IO: Send + Sync + 'static,
+    {
+        let (handoff, accepted) = unbounded();
+        self.handoff = Some(handoff);
+        let requests = Http2Requests {
+            accepted,
+            connections: SelectAll::new(),
+        };
+        let shutdown = self.shutdown.clone();
+        HttpIncoming::new(self)
+            .with_optional_shutdown(shutdown)
+            .with_requests(Box::pin(requests))
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin + PeerAddr, T: Stream<Item = TlsStream<IO>> + Unpin>
+    Http2Incoming<TlsStream<IO>, T>
+{
+    /// Serve HTTP/2 on connections that negotiated `h2` via ALPN and pass other connections on
+    /// as HTTP/1.
+    pub fn negotiated(incoming: T) -> Self {
+        Self::with_detect(incoming, |tls_stream| {
+            tls_stream.get_ref().1.alpn_protocol() == Some(b"h2")
+        })
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin, T: Stream<Item = IO> + Unpin> Unpin
+    for Http2Incoming<IO, T>
+{
+}
+
+impl<
+        IO: AsyncRead + AsyncWrite + Unpin + PeerAddr + HasTlsInfo + ClientCertificate,
+        T: Stream<Item = IO> + Unpin,
+    > Stream for Http2Incoming<IO, T>
+{
+    type Item = Http1OrHttp2Stream<IO>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(shutdown) = &mut self.shutdown {
+            if shutdown.poll_state(cx) != ShutdownState::Running {
+                drop(self.incoming.take());
+            }
+        }
+        loop {
+            match self.connections.poll_next_unpin(cx) {
+                Poll::Ready(Some((request, respond, info))) => {
+                    let stream = Http2Stream {
+                        info,
+                        exchange: Some(Box::new((request, respond))),
+                    };
+                    return Poll::Ready(Some(Http1OrHttp2Stream::Http2(stream)));
+                }
+                Poll::Ready(None) | Poll::Pending => match &mut self.incoming {
+                    Some(incoming) => match incoming.poll_next_unpin(cx) {
+                        Poll::Ready(Some(transport)) => match (self.detect)(&transport) {
+                            true => {
+                                let connection = Http2Connection::new(
+                                    transport,
+                                    &self.builder,
+                                    self.shutdown.clone(),
+                                );
+                                match &self.handoff {
+                                    Some(handoff) => {
+                                        if handoff.unbounded_send(connection).is_err() {
+                                            log::debug!("http2 connection dropped after handoff")
+                                        }
+                                    }
+                                    None => self.connections.push(connection),
+                                }
+                            }
+                            false => {
+                                return Poll::Ready(Some(Http1OrHttp2Stream::Http1(transport)))
+                            }
+                        },
+                        Poll::Ready(None) => drop(self.incoming.take()),
+                        Poll::Pending => return Poll::Pending,
+                    },
+                    None => match self.is_terminated() {
+                        true => return Poll::Ready(None),
+                        false => return Poll::Pending,
+                    },
+                },
+            }
+        }
+    }
+}
+
+impl<
+        IO: AsyncRead + AsyncWrite + Unpin + PeerAddr + HasTlsInfo + ClientCertificate,
+        T: Stream<Item = IO> + Unpin,
+    > FusedStream for Http2Incoming<IO, T>
+{
+    fn is_terminated(&self) -> bool {
+        self.incoming.is_none() && self.connections.is_empty()
+    }
+}
+
+/// Drives the HTTP/2 connections handed over by [Http2Incoming::http], yielding their requests.
+struct Http2Requests<IO: AsyncRead + AsyncWrite + Unpin> {
+    accepted: UnboundedReceiver<Http2Connection<IO>>,
+    connections: SelectAll<Http2Connection<IO>>,
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> Stream for Http2Requests<IO> {
+    type Item = HttpRequest<Http1OrHttp2Stream<IO>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        while let Poll::Ready(Some(connection)) = self.accepted.poll_next_unpin(cx) {
+            self.connections.push(connection);
+        }
+        match self.connections.poll_next_unpin(cx) {
+            Poll::Ready(Some((request, respond, info))) => {
+                let (parts, body) = request.into_parts();
+                Poll::Ready(Some(HttpRequest {
+                    head: request_head(parts),
+                    state: BodyDecodeWithContinueState::new(Version::HTTP_2, Some(0), false),
+                    transport: Http1OrHttp2Stream::Http2(Http2Stream {
+                        info,
+                        exchange: None,
+                    }),
+                    keep_alive: None,
+                    body_timeout: None,
+                    h2: Some(Http2Exchange::new(body, respond)),
+                }))
+            }
+            Poll::Ready(None) | Poll::Pending => {
+                match self.accepted.is_terminated() && self.connections.is_empty() {
+                    true => Poll::Ready(None),
+                    false => Poll::Pending,
+                }
+            }
+        }
+    }
+}
+
+/// Transport properties captured when accepting a connection, shared by its streams.
+struct ConnectionInfo {
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    tls_info: Option<TlsInfo>,
+    peer_certificates: Option<Vec<CertificateDer<'static>>>,
+}
+
+enum ConnectionState<IO: AsyncRead + AsyncWrite + Unpin> {
+    Handshaking(Handshake<Compat<IO>, Bytes>),
+    Serving(Connection<Compat<IO>, Bytes>),
+    Done,
+}
+
+type Accepted = (
+    Request<RecvStream>,
+    SendResponse<Bytes>,
+    Arc<ConnectionInfo>,
+);
+
+/// Drives a single HTTP/2 connection, yielding its streams.
+struct Http2Connection<IO: AsyncRead + AsyncWrite + Unpin> {
+    state: ConnectionState<IO>,
+    info: Arc<ConnectionInfo>,
+    shutdown: Option<ShutdownSignal>,
+    draining: bool,
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin + PeerAddr + HasTlsInfo + ClientCertificate>
+    Http2Connection<IO>
+{
+    fn new(transport: IO, builder: &Builder, shutdown: Option<ShutdownSignal>) -> Self {
+        let info = ConnectionInfo {
+            peer_addr: transport.peer_addr().ok(),
+            local_addr: transport.local_addr().ok(),
+            tls_info: transport.tls_info(),
+            peer_certificates: transport
+                .peer_certificates()
+                .map(|certs| certs.iter().map(|cert| cert.clone().into_owned()).collect()),
+        };
+        Http2Connection {
+            state: ConnectionState::Handshaking(builder.handshake(transport.compat())),
+            info: Arc::new(info),
+            shutdown,
+            draining: false,
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> Stream for Http2Connection<IO> {
+    type Item = Accepted;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if let Some(shutdown) = &mut this.shutdown {
+            match shutdown.poll_state(cx) {
+                ShutdownState::Running => {}
+                ShutdownState::Draining if !this.draining => {
+                    this.draining = true;
+                    if let ConnectionState::Serving(connection) = &mut this.state {
+                        connection.graceful_shutdown();
+                    }
+                }
+                ShutdownState::Draining => {}
+                ShutdownState::Expired => this.state = ConnectionState::Done,
+            }
+        }
+        loop {
+            match &mut this.state {
+                ConnectionState::Handshaking(handshake) => match handshake.poll_unpin(cx) {
+                    Poll::Ready(Ok(mut connection)) => {
+                        if this.draining {
+                            connection.graceful_shutdown();
+                        }
+                        this.state = ConnectionState::Serving(connection);
+                    }
+                    Poll::Ready(Err(err)) => {
+                        log::debug!("http2 handshake error: {:?}", err);
+                        this.state = ConnectionState::Done;
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                ConnectionState::Serving(connection) => match connection.poll_accept(cx) {
+                    Poll::Ready(Some(Ok((request, respond)))) => {
+                        return Poll::Ready(Some((request, respond, this.info.clone())));
+                    }
+                    Poll::Ready(Some(Err(err))) => {
+                        log::debug!("http2 connection error: {:?}", err);
+                        this.state = ConnectionState::Done;
+                    }
+                    Poll::Ready(None) => this.state = ConnectionState::Done,
+                    Poll::Pending => return Poll::Pending,
+                },
+                ConnectionState::Done => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// Either an HTTP/1 connection or a single HTTP/2 stream, see [Http2Incoming].
+pub enum Http1OrHttp2Stream<IO> {
+    Http1(IO),
+    Http2(Http2Stream),
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for Http1OrHttp2Stream<IO> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Http1OrHttp2Stream::Http1(io) => Pin::new(io).poll_read(cx, buf),
+            Http1OrHttp2Stream::Http2(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for Http1OrHttp2Stream<IO> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Http1OrHttp2Stream::Http1(io) => Pin::new(io).poll_write(cx, buf),
+            Http1OrHttp2Stream::Http2(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Http1OrHttp2Stream::Http1(io) => Pin::new(io).poll_flush(cx),
+            Http1OrHttp2Stream::Http2(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Http1OrHttp2Stream::Http1(io) => Pin::new(io).poll_close(cx),
+            Http1OrHttp2Stream::Http2(stream) => Pin::new(stream).poll_close(cx),
+        }
+    }
+}
+
+impl<IO: IsTls> IsTls for Http1OrHttp2Stream<IO> {
+    fn is_tls(&self) -> bool {
+        match self {
+            Http1OrHttp2Stream::Http1(io) => io.is_tls(),
+            Http1OrHttp2Stream::Http2(stream) => stream.is_tls(),
+        }
+    }
+}
+
+impl<IO: PeerAddr> PeerAddr for Http1OrHttp2Stream<IO> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Http1OrHttp2Stream::Http1(io) => io.peer_addr(),
+            Http1OrHttp2Stream::Http2(stream) => stream.peer_addr(),
+        }
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Http1OrHttp2Stream::Http1(io) => io.local_addr(),
+            Http1OrHttp2Stream::Http2(stream) => stream.local_addr(),
+        }
+    }
+}
+
+impl<IO: HasTlsInfo> HasTlsInfo for Http1OrHttp2Stream<IO> {
+    fn tls_info(&self) -> Option<TlsInfo> {
+        match self {
+            Http1OrHttp2Stream::Http1(io) => io.tls_info(),
+            Http1OrHttp2Stream::Http2(stream) => stream.tls_info(),
+        }
+    }
+}
+
+impl<IO: ClientCertificate> ClientCertificate for Http1OrHttp2Stream<IO> {
+    fn peer_certificates(&self) -> Option<&[CertificateDer<'_>]> {
+        match self {
+            Http1OrHttp2Stream::Http1(io) => io.peer_certificates(),
+            Http1OrHttp2Stream::Http2(stream) => stream.peer_certificates(),
+        }
+    }
+}
+
+/// A single HTTP/2 stream as yielded by [Http2Incoming] when not serving it via
+/// [Http2Incoming::http]. As the transport of an [HttpRequest] received over HTTP/2 it only
+/// provides the connection properties, the request body and response are exchanged using the
+/// [HttpRequest] and [HttpResponse](crate::HttpResponse).
+pub struct Http2Stream {
+    info: Arc<ConnectionInfo>,
+    exchange: Option<Box<(Request<RecvStream>, SendResponse<Bytes>)>>,
+}
+
+impl Http2Stream {
+    /// The request and the handle for responding to it.
+    pub fn into_parts(self) -> Option<(Request<RecvStream>, SendResponse<Bytes>)> {
+        self.exchange.map(|exchange| *exchange)
+    }
+}
+
+impl AsyncRead for Http2Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(0))
+    }
+}
+
+impl AsyncWrite for Http2Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "http2 streams cannot be written directly",
+        )))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Request body and response handle of an [HttpRequest] received over HTTP/2.
+pub(crate) struct Http2Exchange {
+    body: Option<RecvStream>,
+    data: Bytes,
+    respond: SendResponse<Bytes>,
+}
+
+impl Http2Exchange {
+    fn new(body: RecvStream, respond: SendResponse<Bytes>) -> Self {
+        Http2Exchange {
+            body: Some(body).filter(|body| !body.is_end_stream()),
+            data: Bytes::new(),
+            respond,
+        }
+    }
+    /// Read request body data, releasing flow control capacity as it is consumed.
+    pub(crate) fn poll_read(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            if !self.data.is_empty() {
+                let n = buf.len().min(self.data.len());
+                buf[..n].copy_from_slice(&self.data.split_to(n));
+                if let Some(body) = &mut self.body {
+                    let _ = body.flow_control().release_capacity(n);
+                }
+                return Poll::Ready(Ok(n));
+            }
+            let body = match &mut self.body {
+                Some(body) => body,
+                None => return Poll::Ready(Ok(0)),
+            };
+            match body.poll_data(cx) {
+                Poll::Ready(Some(Ok(data))) => self.data = data,
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Err(h2_error(err))),
+                Poll::Ready(None) => self.body = None,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+    pub(crate) fn send_response(
+        &mut self,
+        head: &ResponseHead,
+        end_of_stream: bool,
+    ) -> io::Result<SendStream<Bytes>> {
+        self.respond
+            .send_response(response(head), end_of_stream)
+            .map_err(h2_error)
+    }
+    pub(crate) fn send_timeout_response(&mut self) {
+        let mut response = Response::new(());
+        *response.status_mut() = StatusCode::REQUEST_TIMEOUT;
+        if let Err(err) = self.respond.send_response(response, true) {
+            log::debug!("error sending 408: {:?}", err)
+        }
+    }
+}
+
+/// Request head of an HTTP/2 request as it would have been received over HTTP/1.1, with the
+/// target in origin form (authority form for `CONNECT`) and the `:authority` pseudo-header as
+/// `Host` header.
+fn request_head(mut parts: http::request::Parts) -> RequestHead<'static> {
+    if let (false, Some(authority)) = (parts.headers.contains_key(HOST), parts.uri.authority()) {
+        if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+            parts.headers.insert(HOST, host);
+        }
+    }
+    if parts.method != Method::CONNECT {
+        if let Some(path_and_query) = parts.uri.path_and_query() {
+            parts.uri = Uri::from(path_and_query.clone());
+        }
+    }
+    RequestHead::new(
+        parts.method,
+        Cow::Owned(parts.uri),
+        Version::HTTP_2,
+        Cow::Owned(parts.headers),
+    )
+}
+
+/// HTTP/2 response for a response head, without connection-specific headers.
+fn response(head: &ResponseHead) -> Response<()> {
+    let mut response = Response::new(());
+    *response.status_mut() = head.status();
+    for (name, value) in head.headers().iter() {
+        if !is_connection_specific(name) {
+            response.headers_mut().append(name, value.clone());
+        }
+    }
+    response
+}
+
+fn is_connection_specific(name: &HeaderName) -> bool {
+    matches!(
+        name.as_str(),
+        "connection" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "upgrade"
+    )
+}
+
+fn h2_error(err: h2::Error) -> io::Error {
+    match err.reason() {
+        Some(_) => io::Error::new(io::ErrorKind::ConnectionReset, err),
+        None => io::Error::other(err),
+    }
+}
+
+/// Send as much data as the flow control window permits, reserving capacity first so response
+/// data is not buffered without limit.
+pub(crate) fn poll_send_data(
+    send: &mut SendStream<Bytes>,
+    cx: &mut Context<'_>,
+    data: &[u8],
+    end_of_stream: bool,
+) -> Poll<io::Result<usize>> {
+    if data.is_empty() {
+        if end_of_stream {
+            send.send_data(Bytes::new(), true).map_err(h2_error)?;
+        }
+        return Poll::Ready(Ok(0));
+    }
+    send.reserve_capacity(data.len());
+    loop {
+        let capacity = send.capacity();
+        if capacity > 0 {
+            let n = capacity.min(data.len());
+            let end_of_stream = end_of_stream && n == data.len();
+            send.send_data(Bytes::copy_from_slice(&data[..n]), end_of_stream)
+                .map_err(h2_error)?;
+            return Poll::Ready(Ok(n));
+        }
+        match ready!(send.poll_capacity(cx)) {
+            Some(Ok(_)) => {}
+            Some(Err(err)) => return Poll::Ready(Err(h2_error(err))),
+            None => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+}
+
+impl IsTls for Http2Stream {
+    fn is_tls(&self) -> bool {
+        self.info.tls_info.is_some()
+    }
+}
+
+impl PeerAddr for Http2Stream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.info
+            .peer_addr
+            .ok_or_else(|| io::ErrorKind::Unsupported.into())
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.info
+            .local_addr
+            .ok_or_else(|| io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl HasTlsInfo for Http2Stream {
+    fn tls_info(&self) -> Option<TlsInfo> {
+        self.info.tls_info.clone()
+    }
+}
+
+impl ClientCertificate for Http2Stream {
+    fn peer_certificates(&self) -> Option<&[CertificateDer<'_>]> {
+        self.info.peer_certificates.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::{CONNECTION, CONTENT_TYPE, TRANSFER_ENCODING};
+
+    fn head(request: http::request::Builder) -> RequestHead<'static> {
+        request_head(request.body(()).unwrap().into_parts().0)
+    }
+
+    #[test]
+    fn origin_form_with_host() {
+        let head = head(
+            Request::builder()
+                .method(Method::POST)
+                .uri("https://example.com:8443/path?query")
+                .header(CONTENT_TYPE, "text/plain"),
+        );
+        assert_eq!(head.method(), Method::POST);
+        assert_eq!(head.uri(), "/path?query");
+        assert_eq!(head.version(), Version::HTTP_2);
+        assert_eq!(head.headers()[HOST], "example.com:8443");
+        assert_eq!(head.headers()[CONTENT_TYPE], "text/plain");
+    }
+
+    #[test]
+    fn host_header_takes_precedence() {
+        let head = head(
+            Request::builder()
+                .uri("https://authority.example/")
+                .header(HOST, "host.example"),
+        );
+        assert_eq!(head.uri(), "/");
+        let hosts: Vec<_> = head.headers().get_all(HOST).iter().collect();
+        assert_eq!(hosts, ["host.example"]);
+    }
+
+    #[test]
+    fn without_authority() {
+        let head = head(Request::builder().uri("/path"));
+        assert_eq!(head.uri(), "/path");
+        assert!(!head.headers().contains_key(HOST));
+    }
+
+    #[test]
+    fn connect_authority_form() {
+        let head = head(
+            Request::builder()
+                .method(Method::CONNECT)
+                .uri("example.com:443"),
+        );
+        assert_eq!(head.method(), Method::CONNECT);
+        assert_eq!(head.uri(), "example.com:443");
+        assert_eq!(head.headers()[HOST], "example.com:443");
+    }
+
+    #[test]
+    fn response_without_connection_specific_headers() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert(CONNECTION, HeaderValue::from_static("close"));
+        headers.insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+        headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        headers.append(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        headers.append("x-multi", HeaderValue::from_static("a"));
+        headers.append("x-multi", HeaderValue::from_static("b"));
+        let head = ResponseHead::new(StatusCode::CREATED, Version::HTTP_2, Cow::Owned(headers));
+        let response = response(&head);
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers().len(), 3);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/plain");
+        let multi: Vec<_> = response.headers().get_all("x-multi").iter().collect();
+        assert_eq!(multi, ["a", "b"]);
+    }
+}
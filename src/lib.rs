@@ -1,16 +1,48 @@
 mod acme;
+mod alpn;
+mod cert_store;
+mod client_auth;
 mod h1;
+mod http2;
+#[cfg(unix)]
+mod listen_fds;
+mod proxy_protocol;
+mod reload;
+mod shutdown;
+mod sniff;
 mod tcp;
 mod tcp_or_tls;
 mod tls;
+#[cfg(unix)]
+mod unix;
 mod ws;
+mod ws_config;
+mod ws_heartbeat;
+mod ws_hub;
+mod ws_transport;
 
 pub use acme::*;
+pub use alpn::*;
+pub use cert_store::*;
+pub use client_auth::*;
 pub use h1::*;
+pub use http2::*;
+#[cfg(unix)]
+pub use listen_fds::*;
+pub use proxy_protocol::*;
+pub use reload::*;
+pub use shutdown::*;
+pub use sniff::*;
 pub use tcp::*;
 pub use tcp_or_tls::*;
 pub use tls::*;
+#[cfg(unix)]
+pub use unix::*;
 pub use ws::*;
+pub use ws_config::*;
+pub use ws_heartbeat::*;
+pub use ws_hub::*;
+pub use ws_transport::*;
 
 pub use async_http_codec;
 pub use async_net;
@@ -0,0 +1,191 @@
+use crate::unix::socket_domain;
+use crate::{TcpIncoming, UnixIncoming};
+use std::env;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// First file descriptor passed by the service manager, see `sd_listen_fds(3)`.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Set once the passed file descriptors are owned by a [ListenFds].
+static ADOPTED: AtomicBool = AtomicBool::new(false);
+
+/// Listening sockets passed by systemd socket activation (`LISTEN_FDS`, `LISTEN_FDNAMES`).
+///
+/// ```no_run
+/// # use async_web_server::ListenFds;
+/// let mut fds = ListenFds::from_env()?;
+/// let http = fds.take_tcp("http")?.http();
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct ListenFds {
+    fds: Vec<(String, Option<OwnedFd>)>,
+}
+
+impl ListenFds {
+    /// Take ownership of the file descriptors passed to this process. Without socket activation
+    /// the result is empty, as is any later call, so the descriptors cannot be adopted twice.
+    ///
+    /// The `LISTEN_*` environment variables are left untouched, as modifying the environment is
+    /// not thread-safe. Child processes ignore them, since `LISTEN_PID` does not match their
+    /// process id and the descriptors are closed on exec.
+    pub fn from_env() -> io::Result<Self> {
+        if ADOPTED.swap(true, Ordering::SeqCst) {
+            return Ok(ListenFds { fds: Vec::new() });
+        }
+        let fds = parse_env(
+            env::var("LISTEN_PID").ok().as_deref(),
+            env::var("LISTEN_FDS").ok().as_deref(),
+            env::var("LISTEN_FDNAMES").ok().as_deref(),
+            std::process::id(),
+        )?
+        .into_iter()
+        .map(|(name, fd)| {
+            set_cloexec(fd)?;
+            Ok((name, Some(unsafe { OwnedFd::from_raw_fd(fd) })))
+        })
+        .collect::<io::Result<_>>()?;
+        Ok(ListenFds { fds })
+    }
+    /// Number of passed file descriptors, including ones already taken.
+    pub fn len(&self) -> usize {
+        self.fds.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.fds.is_empty()
+    }
+    /// Names from `FileDescriptorName=` in declaration order, `unknown` if unnamed.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.fds.iter().map(|(name, _)| name.as_str())
+    }
+    /// Take the file descriptor at `index`.
+    pub fn take(&mut self, index: usize) -> Option<OwnedFd> {
+        self.fds.get_mut(index)?.1.take()
+    }
+    /// Take the first remaining file descriptor with the given name.
+    pub fn take_named(&mut self, name: &str) -> Option<OwnedFd> {
+        self.take_where(Some(name), |_| true)
+    }
+    /// Take the first remaining internet socket with the given name as TCP listener.
+    pub fn take_tcp(&mut self, name: &str) -> io::Result<TcpIncoming> {
+        let fd = self.take_where(Some(name), |fd| {
+            matches!(socket_domain(fd), Ok(libc::AF_INET | libc::AF_INET6))
+        });
+        TcpIncoming::from_fd(fd.ok_or_else(|| not_found(name))?)
+    }
+    /// Take the first remaining Unix domain socket with the given name as listener.
+    pub fn take_unix(&mut self, name: &str) -> io::Result<UnixIncoming> {
+        let fd = self.take_where(Some(name), |fd| {
+            matches!(socket_domain(fd), Ok(libc::AF_UNIX))
+        });
+        UnixIncoming::from_fd(fd.ok_or_else(|| not_found(name))?)
+    }
+    /// Take all remaining internet sockets as TCP listeners.
+    pub fn take_all_tcp(&mut self) -> io::Result<Vec<TcpIncoming>> {
+        let mut incomings = Vec::new();
+        while let Some(fd) = self.take_where(None, |fd| {
+            matches!(socket_domain(fd), Ok(libc::AF_INET | libc::AF_INET6))
+        }) {
+            incomings.push(TcpIncoming::from_fd(fd)?);
+        }
+        Ok(incomings)
+    }
+    fn take_where(&mut self, name: Option<&str>, f: impl Fn(RawFd) -> bool) -> Option<OwnedFd> {
+        self.fds
+            .iter_mut()
+            .filter(|(fd_name, _)| match name {
+                Some(name) => fd_name == name,
+                None => true,
+            })
+            .find(|(_, fd)| matches!(fd, Some(fd) if f(fd.as_raw_fd())))
+            .and_then(|(_, fd)| fd.take())
+    }
+}
+
+/// Names and numbers of the file descriptors passed to the process with id `own_pid`.
+fn parse_env(
+    pid: Option<&str>,
+    count: Option<&str>,
+    names: Option<&str>,
+    own_pid: u32,
+) -> io::Result<Vec<(String, RawFd)>> {
+    let count = match (pid, count) {
+        (Some(pid), Some(count)) if pid.trim() == own_pid.to_string() => count
+            .trim()
+            .parse::<RawFd>()
+            .ok()
+            .filter(|count| (0..=RawFd::MAX - LISTEN_FDS_START).contains(count))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid LISTEN_FDS"))?,
+        _ => 0,
+    };
+    let mut names = names.unwrap_or_default().split(':');
+    let fds = (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| match names.next() {
+            Some(name) if !name.is_empty() => (name.to_string(), fd),
+            _ => ("unknown".to_string(), fd),
+        })
+        .collect();
+    Ok(fds)
+}
+
+fn not_found(name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no matching listening socket named {:?} passed", name),
+    )
+}
+
+fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(pid: Option<&str>, count: Option<&str>, names: Option<&str>) -> Vec<(String, RawFd)> {
+        parse_env(pid, count, names, 42).unwrap()
+    }
+
+    #[test]
+    fn named_fds() {
+        let fds = parse(Some("42"), Some("3"), Some("http:https:admin"));
+        let expected = [("http", 3), ("https", 4), ("admin", 5)];
+        let expected = expected.map(|(name, fd)| (name.to_string(), fd));
+        assert_eq!(fds, expected);
+    }
+
+    #[test]
+    fn missing_or_empty_names() {
+        let fds = parse(Some("42"), Some("3"), Some("http::"));
+        assert_eq!(fds[0].0, "http");
+        assert_eq!(fds[1].0, "unknown");
+        assert_eq!(fds[2].0, "unknown");
+        let fds = parse(Some("42"), Some("2"), None);
+        assert!(fds.iter().all(|(name, _)| name == "unknown"));
+        let fds = parse(Some("42"), Some("1"), Some("http:https"));
+        assert_eq!(fds, [("http".to_string(), 3)]);
+    }
+
+    #[test]
+    fn other_process() {
+        assert!(parse(Some("43"), Some("2"), Some("http:https")).is_empty());
+        assert!(parse(None, Some("2"), None).is_empty());
+        assert!(parse(Some("42"), None, None).is_empty());
+        assert!(parse(Some("42 "), Some(" 1\n"), None).len() == 1);
+    }
+
+    #[test]
+    fn invalid_count() {
+        for count in ["", "x", "-1", "1.5", "2147483647"] {
+            let err = parse_env(Some("42"), Some(count), None, 42).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", count);
+        }
+        assert!(parse(Some("42"), Some("0"), None).is_empty());
+    }
+}
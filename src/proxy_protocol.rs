@@ -0,0 +1,624 @@
+use crate::h1::HttpIncoming;
+use crate::shutdown::ShutdownState;
+use crate::tls::single_cert_config;
+use crate::{IsTls, PeerAddr, ShutdownSignal, TcpIncoming, TcpStream, TlsIncoming, TlsStream};
+use async_io::Timer;
+use futures::future::{select, Either};
+use futures::prelude::*;
+use futures::stream::{FusedStream, FuturesUnordered};
+use rustls_acme::futures_rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls_acme::futures_rustls::rustls::server::ClientHello;
+use rustls_acme::futures_rustls::rustls::ServerConfig;
+use std::convert::TryInto;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Maximum length of a v1 header including the trailing CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+pub const PP2_TYPE_ALPN: u8 = 0x01;
+pub const PP2_TYPE_AUTHORITY: u8 = 0x02;
+
+/// Header sent by a proxy in front of the server, see
+/// <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ProxyHeader {
+    /// Protocol version, 1 for the text and 2 for the binary format.
+    pub version: u8,
+    /// Address of the original client. `None` for connections established by the proxy itself
+    /// (e.g. health checks) or unsupported address families.
+    pub source: Option<SocketAddr>,
+    /// Address the original client connected to.
+    pub destination: Option<SocketAddr>,
+    /// Type-length-value fields of a v2 header.
+    pub tlvs: Vec<(u8, Vec<u8>)>,
+}
+
+impl ProxyHeader {
+    pub fn tlv(&self, kind: u8) -> Option<&[u8]> {
+        self.tlvs
+            .iter()
+            .find(|(tlv_kind, _)| *tlv_kind == kind)
+            .map(|(_, value)| value.as_slice())
+    }
+    /// ALPN protocol negotiated by the proxy with the original client.
+    pub fn alpn(&self) -> Option<&[u8]> {
+        self.tlv(PP2_TYPE_ALPN)
+    }
+    /// Host name presented by the original client (e.g. via SNI).
+    pub fn authority(&self) -> Option<&str> {
+        std::str::from_utf8(self.tlv(PP2_TYPE_AUTHORITY)?).ok()
+    }
+}
+
+pub trait ProxyInfo {
+    /// PROXY protocol header received on the underlying connection.
+    fn proxy_header(&self) -> Option<&ProxyHeader>;
+}
+
+impl ProxyInfo for ProxyStream {
+    fn proxy_header(&self) -> Option<&ProxyHeader> {
+        Some(&self.header)
+    }
+}
+
+impl<IO: ProxyInfo> ProxyInfo for TlsStream<IO> {
+    fn proxy_header(&self) -> Option<&ProxyHeader> {
+        self.get_ref().0.proxy_header()
+    }
+}
+
+/// Stream with the PROXY protocol header already received. The [PeerAddr] implementation reports
+/// the addresses from the header, falling back to the addresses of the connection to the proxy.
+pub struct ProxyStream {
+    stream: TcpStream,
+    header: ProxyHeader,
+}
+
+impl ProxyStream {
+    pub fn header(&self) -> &ProxyHeader {
+        &self.header
+    }
+    /// Underlying connection to the proxy.
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+    pub fn into_inner(self) -> (TcpStream, ProxyHeader) {
+        (self.stream, self.header)
+    }
+}
+
+impl PeerAddr for ProxyStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self.header.source {
+            Some(source) => Ok(source),
+            None => PeerAddr::peer_addr(&self.stream),
+        }
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.header.destination {
+            Some(destination) => Ok(destination),
+            None => PeerAddr::local_addr(&self.stream),
+        }
+    }
+}
+
+impl IsTls for ProxyStream {
+    fn is_tls(&self) -> bool {
+        false
+    }
+}
+
+impl AsyncRead for ProxyStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxyStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_close(cx)
+    }
+}
+
+type HeaderDecode = Pin<Box<dyn Future<Output = io::Result<ProxyStream>> + Send>>;
+
+/// Stream of connections preceded by a PROXY protocol v1 or v2 header, created using
+/// [TcpIncoming::proxy_protocol]. Only connections from networks added using
+/// [ProxyIncoming::trust] are accepted, as anyone able to connect could spoof addresses otherwise.
+/// Connections without a valid header are closed.
+pub struct ProxyIncoming {
+    tcp_incoming: Option<TcpIncoming>,
+    decoding: FuturesUnordered<HeaderDecode>,
+    trusted: Vec<(IpAddr, u8)>,
+    header_timeout: Duration,
+    pub(crate) shutdown: Option<ShutdownSignal>,
+}
+
+impl ProxyIncoming {
+    pub fn new(tcp_incoming: TcpIncoming) -> Self {
+        let shutdown = tcp_incoming.shutdown.clone();
+        ProxyIncoming {
+            tcp_incoming: Some(tcp_incoming),
+            decoding: FuturesUnordered::new(),
+            trusted: Vec::new(),
+            header_timeout: Duration::from_secs(5),
+            shutdown,
+        }
+    }
+    /// Accept connections from proxies within the given network, e.g. `trust([10, 0, 0, 0], 8)`.
+    /// May be called repeatedly. Without any trusted networks all connections are rejected.
+    pub fn trust(mut self, network: impl Into<IpAddr>, prefix_len: u8) -> Self {
+        self.trusted.push((network.into(), prefix_len));
+        self
+    }
+    /// Limit the time for receiving the header (5 seconds by default).
+    pub fn header_timeout(mut self, timeout: Duration) -> Self {
+        self.header_timeout = timeout;
+        self
+    }
+    /// Stop accepting connections once shutdown is triggered and abandon headers still pending
+    /// at the deadline, see [Shutdown](crate::Shutdown).
+    pub fn graceful_shutdown(mut self, signal: ShutdownSignal) -> Self {
+        self.shutdown = Some(signal);
+        self
+    }
+    pub fn tls_with_config<F: FnMut(&ClientHello) -> Arc<ServerConfig>>(
+        self,
+        f: F,
+    ) -> TlsIncoming<F, ProxyStream, Self> {
+        let shutdown = self.shutdown.clone();
+        TlsIncoming::with_transport(self, f).with_optional_shutdown(shutdown)
+    }
+    #[allow(clippy::type_complexity)]
+    pub fn tls(
+        self,
+        cert_chain: Vec<CertificateDer<'static>>,
+        key_der: PrivateKeyDer<'static>,
+    ) -> Result<
+        TlsIncoming<impl FnMut(&ClientHello) -> Arc<ServerConfig>, ProxyStream, Self>,
+        rustls_acme::futures_rustls::rustls::Error,
+    > {
+        let config = single_cert_config(cert_chain, key_der, None)?;
+        Ok(self.tls_with_config(move |_| config.clone()))
+    }
+    pub fn http(self) -> HttpIncoming<ProxyStream, Self> {
+        let shutdown = self.shutdown.clone();
+        HttpIncoming::new(self).with_optional_shutdown(shutdown)
+    }
+    fn is_trusted(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => IpAddr::V4(v4),
+                None => IpAddr::V6(v6),
+            },
+            addr => addr,
+        };
+        self.trusted
+            .iter()
+            .any(|(network, prefix_len)| in_network(addr, *network, *prefix_len))
+    }
+}
+
+fn in_network(addr: IpAddr, network: IpAddr, prefix_len: u8) -> bool {
+    fn prefix_eq(a: u128, b: u128, bits: u32, prefix_len: u8) -> bool {
+        let prefix_len = (prefix_len as u32).min(bits);
+        match prefix_len {
+            0 => true,
+            _ => (a ^ b) >> (bits - prefix_len) == 0,
+        }
+    }
+    match (addr, network) {
+        (IpAddr::V4(a), IpAddr::V4(n)) => {
+            prefix_eq(u32::from(a) as u128, u32::from(n) as u128, 32, prefix_len)
+        }
+        (IpAddr::V6(a), IpAddr::V6(n)) => prefix_eq(u128::from(a), u128::from(n), 128, prefix_len),
+        _ => false,
+    }
+}
+
+impl Stream for ProxyIncoming {
+    type Item = ProxyStream;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(shutdown) = &mut self.shutdown {
+            match shutdown.poll_state(cx) {
+                ShutdownState::Running => {}
+                ShutdownState::Draining => drop(self.tcp_incoming.take()),
+                ShutdownState::Expired => {
+                    drop(self.tcp_incoming.take());
+                    self.decoding.clear();
+                }
+            }
+        }
+        loop {
+            match self.decoding.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(stream))) => return Poll::Ready(Some(stream)),
+                Poll::Ready(Some(Err(err))) => log::debug!("proxy protocol error: {:?}", err),
+                Poll::Ready(None) | Poll::Pending => match &mut self.tcp_incoming {
+                    None => match self.is_terminated() {
+                        true => return Poll::Ready(None),
+                        false => return Poll::Pending,
+                    },
+                    Some(tcp_incoming) => match tcp_incoming.poll_next_unpin(cx) {
+                        Poll::Ready(Some(stream)) => match stream.peer_addr() {
+                            Ok(addr) if self.is_trusted(addr.ip()) => {
+                                let timeout = self.header_timeout;
+                                self.decoding.push(Box::pin(async move {
+                                    let decode = Box::pin(async move {
+                                        let mut stream = stream;
+                                        let header = read_header(&mut stream).await?;
+                                        Ok(ProxyStream { stream, header })
+                                    });
+                                    match select(decode, Timer::after(timeout)).await {
+                                        Either::Left((result, _)) => result,
+                                        Either::Right(_) => Err(io::ErrorKind::TimedOut.into()),
+                                    }
+                                }))
+                            }
+                            Ok(addr) => log::debug!("untrusted proxy protocol source: {}", addr),
+                            Err(err) => log::debug!("tcp peer address error: {:?}", err),
+                        },
+                        Poll::Ready(None) => drop(self.tcp_incoming.take()),
+                        Poll::Pending => return Poll::Pending,
+                    },
+                },
+            }
+        }
+    }
+}
+
+impl FusedStream for ProxyIncoming {
+    fn is_terminated(&self) -> bool {
+        self.tcp_incoming.is_none() && self.decoding.is_terminated()
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Read the header without consuming any bytes beyond it. The shortest valid header (v1
+/// `PROXY UNKNOWN\r\n`) is longer than the v2 signature, so reading the first 12 bytes is safe.
+async fn read_header<IO: AsyncRead + Unpin>(stream: &mut IO) -> io::Result<ProxyHeader> {
+    let mut buf = vec![0u8; V2_SIGNATURE.len()];
+    stream.read_exact(&mut buf).await?;
+    let header = if buf == V2_SIGNATURE {
+        let mut fixed = [0u8; 4];
+        stream.read_exact(&mut fixed).await?;
+        let len = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).await?;
+        parse_v2(fixed[0], fixed[1], &payload)?
+    } else if buf.starts_with(b"PROXY ") {
+        while !buf.ends_with(b"\r\n") {
+            if buf.len() >= V1_MAX_LENGTH {
+                return Err(invalid("proxy protocol v1 header too long"));
+            }
+            let mut byte = [0u8];
+            stream.read_exact(&mut byte).await?;
+            buf.push(byte[0]);
+        }
+        parse_v1(&buf[..buf.len() - 2])?
+    } else {
+        return Err(invalid("missing proxy protocol header"));
+    };
+    Ok(header)
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<ProxyHeader> {
+    let line =
+        std::str::from_utf8(line).map_err(|_| invalid("invalid proxy protocol v1 header"))?;
+    let mut parts = line.split(' ').skip(1);
+    let mut header = ProxyHeader {
+        version: 1,
+        ..ProxyHeader::default()
+    };
+    match parts.next() {
+        Some("TCP4") | Some("TCP6") => {}
+        Some("UNKNOWN") => return Ok(header),
+        _ => return Err(invalid("invalid proxy protocol v1 header")),
+    }
+    let parts: Vec<&str> = parts.collect();
+    let (source, destination, source_port, destination_port) = match parts.as_slice() {
+        [source, destination, source_port, destination_port] => {
+            (source, destination, source_port, destination_port)
+        }
+        _ => return Err(invalid("invalid proxy protocol v1 header")),
+    };
+    let parse = |ip: &str, port: &str| -> io::Result<SocketAddr> {
+        let ip = ip.parse::<IpAddr>();
+        let port = port.parse::<u16>();
+        match (ip, port) {
+            (Ok(ip), Ok(port)) => Ok(SocketAddr::new(ip, port)),
+            _ => Err(invalid("invalid proxy protocol v1 address")),
+        }
+    };
+    header.source = Some(parse(source, source_port)?);
+    header.destination = Some(parse(destination, destination_port)?);
+    Ok(header)
+}
+
+fn parse_v2(version_command: u8, family: u8, payload: &[u8]) -> io::Result<ProxyHeader> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported proxy protocol version"));
+    }
+    let mut header = ProxyHeader {
+        version: 2,
+        ..ProxyHeader::default()
+    };
+    let local = match version_command & 0x0f {
+        0x0 => true,
+        0x1 => false,
+        _ => return Err(invalid("unsupported proxy protocol command")),
+    };
+    let address_len = match family >> 4 {
+        0x0 => 0,
+        0x1 => 12,
+        0x2 => 36,
+        0x3 => 216,
+        _ => return Err(invalid("unsupported proxy protocol address family")),
+    };
+    // Only connections proxied over a stream transport (e.g. TCP) are served, while the address
+    // block of LOCAL and AF_UNSPEC headers is ignored anyway.
+    if !local && family != 0x00 && family & 0x0f != 0x1 {
+        return Err(invalid("unsupported proxy protocol transport"));
+    }
+    if payload.len() < address_len {
+        return Err(invalid("truncated proxy protocol v2 header"));
+    }
+    let (addresses, mut tlvs) = payload.split_at(address_len);
+    if !local {
+        let (source, destination) = match family >> 4 {
+            0x1 => {
+                let ip = |b: &[u8]| IpAddr::V4(Ipv4Addr::new(b[0], b[1], b[2], b[3]));
+                let port = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+                (
+                    Some(SocketAddr::new(
+                        ip(&addresses[0..4]),
+                        port(&addresses[8..10]),
+                    )),
+                    Some(SocketAddr::new(
+                        ip(&addresses[4..8]),
+                        port(&addresses[10..12]),
+                    )),
+                )
+            }
+            0x2 => {
+                let ip = |b: &[u8]| {
+                    let octets: [u8; 16] = b.try_into().unwrap();
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                };
+                let port = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+                (
+                    Some(SocketAddr::new(
+                        ip(&addresses[0..16]),
+                        port(&addresses[32..34]),
+                    )),
+                    Some(SocketAddr::new(
+                        ip(&addresses[16..32]),
+                        port(&addresses[34..36]),
+                    )),
+                )
+            }
+            _ => (None, None),
+        };
+        header.source = source;
+        header.destination = destination;
+    }
+    while !tlvs.is_empty() {
+        if tlvs.len() < 3 {
+            return Err(invalid("truncated proxy protocol v2 tlv"));
+        }
+        let kind = tlvs[0];
+        let len = u16::from_be_bytes([tlvs[1], tlvs[2]]) as usize;
+        if tlvs.len() < 3 + len {
+            return Err(invalid("truncated proxy protocol v2 tlv"));
+        }
+        header.tlvs.push((kind, tlvs[3..3 + len].to_vec()));
+        tlvs = &tlvs[3 + len..];
+    }
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn read(data: &[u8]) -> io::Result<(ProxyHeader, Vec<u8>)> {
+        let mut stream = futures::io::Cursor::new(data);
+        let header = block_on(read_header(&mut stream))?;
+        let mut rest = Vec::new();
+        block_on(stream.read_to_end(&mut rest))?;
+        Ok((header, rest))
+    }
+
+    fn addr(s: &str) -> Option<SocketAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    fn v2(command: u8, family: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20 | command, family]);
+        header.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        header.extend_from_slice(payload);
+        header
+    }
+
+    const V4_ADDRESSES: [u8; 12] = [192, 0, 2, 1, 198, 51, 100, 2, 0x30, 0x39, 0x01, 0xbb];
+
+    #[test]
+    fn v1_tcp4() {
+        let (header, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.2 12345 443\r\nGET /").unwrap();
+        assert_eq!(header.version, 1);
+        assert_eq!(header.source, addr("192.0.2.1:12345"));
+        assert_eq!(header.destination, addr("198.51.100.2:443"));
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[test]
+    fn v1_tcp6_and_unknown() {
+        let (header, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 1 2\r\n").unwrap();
+        assert_eq!(header.source, addr("[2001:db8::1]:1"));
+        assert_eq!(header.destination, addr("[2001:db8::2]:2"));
+        let (header, rest) = read(b"PROXY UNKNOWN\r\nx").unwrap();
+        assert_eq!(header.source, None);
+        assert_eq!(rest, b"x");
+    }
+
+    #[test]
+    fn v1_malformed() {
+        for line in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.2 12345\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 12345 443 1\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 12345 65536\r\n",
+            b"PROXY TCP4 192.0.2 198.51.100.2 12345 443\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.2 12345 443\r\n",
+            b"PROXY TCP4  192.0.2.1 198.51.100.2 12345 443\r\n",
+            b"PROXY TCP4 \xff 198.51.100.2 12345 443\r\n",
+            b"GET / HTTP/1.1\r\n\r\n",
+        ] {
+            let err = read(line).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn v1_too_long_or_truncated() {
+        let mut line = b"PROXY TCP6 ".to_vec();
+        line.resize(200, b'1');
+        assert_eq!(read(&line).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        for line in [&b"PROXY TCP4 192.0.2.1"[..], b"PROXY TC", b""] {
+            let err = read(line).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn v2_tcp4() {
+        let mut data = v2(0x1, 0x11, &V4_ADDRESSES);
+        data.extend_from_slice(b"GET /");
+        let (header, rest) = read(&data).unwrap();
+        assert_eq!(header.version, 2);
+        assert_eq!(header.source, addr("192.0.2.1:12345"));
+        assert_eq!(header.destination, addr("198.51.100.2:443"));
+        assert!(header.tlvs.is_empty());
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[test]
+    fn v2_tcp6() {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        payload.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        payload.extend_from_slice(&[0, 1, 0, 2]);
+        let (header, _) = read(&v2(0x1, 0x21, &payload)).unwrap();
+        assert_eq!(header.source, addr("[2001:db8::1]:1"));
+        assert_eq!(header.destination, addr("[2001:db8::2]:2"));
+    }
+
+    #[test]
+    fn v2_local_and_unix() {
+        let (header, _) = read(&v2(0x0, 0x11, &V4_ADDRESSES)).unwrap();
+        assert_eq!(header.source, None);
+        assert_eq!(header.destination, None);
+        let (header, _) = read(&v2(0x0, 0x12, &V4_ADDRESSES)).unwrap();
+        assert_eq!(header.source, None);
+        let (header, _) = read(&v2(0x1, 0x31, &[0u8; 216])).unwrap();
+        assert_eq!(header.source, None);
+        let (header, _) = read(&v2(0x0, 0x00, &[])).unwrap();
+        assert_eq!(
+            header,
+            ProxyHeader {
+                version: 2,
+                ..ProxyHeader::default()
+            }
+        );
+    }
+
+    #[test]
+    fn v2_tlvs() {
+        let mut payload = V4_ADDRESSES.to_vec();
+        payload.extend_from_slice(&[PP2_TYPE_ALPN, 0, 2]);
+        payload.extend_from_slice(b"h2");
+        payload.extend_from_slice(&[PP2_TYPE_AUTHORITY, 0, 11]);
+        payload.extend_from_slice(b"example.com");
+        payload.extend_from_slice(&[0xee, 0, 0]);
+        let (header, _) = read(&v2(0x1, 0x11, &payload)).unwrap();
+        assert_eq!(header.alpn(), Some(&b"h2"[..]));
+        assert_eq!(header.authority(), Some("example.com"));
+        assert_eq!(header.tlv(0xee), Some(&[][..]));
+        assert_eq!(header.tlv(0x05), None);
+    }
+
+    #[test]
+    fn v2_malformed() {
+        let mut truncated_tlv = V4_ADDRESSES.to_vec();
+        truncated_tlv.extend_from_slice(&[PP2_TYPE_ALPN, 0, 3, b'h', b'2']);
+        let mut short_tlv = V4_ADDRESSES.to_vec();
+        short_tlv.extend_from_slice(&[PP2_TYPE_ALPN, 0]);
+        let mut wrong_version = v2(0x1, 0x11, &V4_ADDRESSES);
+        wrong_version[12] = 0x11;
+        for data in [
+            v2(0x2, 0x11, &V4_ADDRESSES),
+            v2(0x1, 0x41, &V4_ADDRESSES),
+            v2(0x1, 0x10, &V4_ADDRESSES),
+            v2(0x1, 0x12, &V4_ADDRESSES),
+            v2(0x1, 0x13, &V4_ADDRESSES),
+            v2(0x1, 0x11, &V4_ADDRESSES[..11]),
+            v2(0x1, 0x21, &V4_ADDRESSES),
+            v2(0x1, 0x11, &truncated_tlv),
+            v2(0x1, 0x11, &short_tlv),
+            wrong_version,
+        ] {
+            let err = read(&data).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn v2_truncated() {
+        let data = v2(0x1, 0x11, &V4_ADDRESSES);
+        for len in [5, 12, 14, 16, 20, data.len() - 1] {
+            let err = read(&data[..len]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "{}", len);
+        }
+    }
+
+    #[test]
+    fn trusted_networks() {
+        let tcp = TcpIncoming::bind(([127, 0, 0, 1], 0)).unwrap();
+        let incoming = ProxyIncoming::new(tcp);
+        assert!(!incoming.is_trusted([10, 0, 0, 1].into()));
+        let incoming = incoming
+            .trust([10, 0, 0, 0], 8)
+            .trust("2001:db8::".parse::<Ipv6Addr>().unwrap(), 32);
+        assert!(incoming.is_trusted([10, 1, 2, 3].into()));
+        assert!(!incoming.is_trusted([11, 0, 0, 1].into()));
+        assert!(incoming.is_trusted("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(incoming.is_trusted("2001:db8:1::1".parse().unwrap()));
+        assert!(!incoming.is_trusted("2001:db9::1".parse().unwrap()));
+        let incoming = incoming.trust([0, 0, 0, 0], 0);
+        assert!(incoming.is_trusted([192, 0, 2, 1].into()));
+    }
+}
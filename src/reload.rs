@@ -0,0 +1,240 @@
+use crate::parse_pem;
+use crate::tls::single_cert_config;
+use rustls_acme::futures_rustls::rustls::ServerConfig;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Certificate loaded from PEM files that can be replaced without restarting the listener, see
+/// [TcpIncoming::tls_reloadable](crate::TcpIncoming::tls_reloadable).
+///
+/// Clones share the same certificate and act as handles to trigger a [Self::reload]. With
+/// [Self::watch] enabled, a background thread also checks the files for modifications, so
+/// handshakes never wait for the file system. A new certificate is only used if it parses and
+/// matches its private key, otherwise the error is logged and the previous certificate is kept.
+/// Handshakes in progress are not affected.
+///
+/// ```no_run
+/// # use async_web_server::{ReloadableCert, TcpIncoming};
+/// # use std::time::Duration;
+/// let cert = ReloadableCert::from_files("cert.pem", "key.pem")?.watch(Duration::from_secs(10))?;
+/// let incoming = TcpIncoming::bind(([0, 0, 0, 0], 443))?.tls_reloadable(cert.clone());
+/// // e.g. on SIGHUP
+/// cert.reload()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Clone)]
+pub struct ReloadableCert {
+    inner: Arc<ReloadInner>,
+}
+
+struct ReloadInner {
+    paths: Vec<PathBuf>,
+    config: RwLock<Arc<ServerConfig>>,
+    /// Incremented by [ReloadableCert::watch], stopping the thread of a previous call.
+    watch_generation: AtomicU64,
+}
+
+impl ReloadableCert {
+    /// Load the certificate chain and private key from a single PEM file.
+    pub fn from_pem_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::load(vec![path.as_ref().to_path_buf()])
+    }
+    /// Load the certificate chain and private key from separate PEM files.
+    pub fn from_files(cert_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::load(vec![
+            cert_path.as_ref().to_path_buf(),
+            key_path.as_ref().to_path_buf(),
+        ])
+    }
+    fn load(paths: Vec<PathBuf>) -> io::Result<Self> {
+        let config = read_config(&paths)?;
+        Ok(ReloadableCert {
+            inner: Arc::new(ReloadInner {
+                paths,
+                config: RwLock::new(config),
+                watch_generation: AtomicU64::new(0),
+            }),
+        })
+    }
+    /// Reload the certificate when the modification time of its files changes, checking once per
+    /// interval on a background thread. The thread exits once all handles are dropped.
+    pub fn watch(self, interval: Duration) -> io::Result<Self> {
+        let generation = self.inner.watch_generation.fetch_add(1, Ordering::Relaxed) + 1;
+        let inner = Arc::downgrade(&self.inner);
+        let mut modified = self.modified();
+        thread::Builder::new()
+            .name("cert-watch".to_string())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                let cert = match inner.upgrade() {
+                    Some(inner) if inner.watch_generation.load(Ordering::Relaxed) == generation => {
+                        ReloadableCert { inner }
+                    }
+                    _ => return,
+                };
+                let current = cert.modified();
+                // Also remember failed attempts, a partially written update is retried once the
+                // remaining files are modified.
+                if current != modified {
+                    modified = current;
+                    let _ = cert.reload();
+                }
+            })?;
+        Ok(self)
+    }
+    /// Re-read the files and use the new certificate for new handshakes. On failure the previous
+    /// certificate is kept.
+    pub fn reload(&self) -> io::Result<()> {
+        match read_config(&self.inner.paths) {
+            Ok(config) => {
+                *self.inner.config.write().unwrap() = config;
+                log::info!("reloaded certificate from {:?}", self.inner.paths);
+                Ok(())
+            }
+            Err(err) => {
+                log::error!(
+                    "keeping previous certificate, reloading {:?} failed: {:?}",
+                    self.inner.paths,
+                    err
+                );
+                Err(err)
+            }
+        }
+    }
+    /// Configuration used for new handshakes.
+    pub fn server_config(&self) -> Arc<ServerConfig> {
+        self.inner.config.read().unwrap().clone()
+    }
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.inner.paths.iter().map(|path| modified(path)).collect()
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn read_config(paths: &[PathBuf]) -> io::Result<Arc<ServerConfig>> {
+    let mut pem = Vec::new();
+    for path in paths {
+        pem.extend(fs::read(path)?);
+        pem.push(b'\n');
+    }
+    let (cert_chain, key_der) = parse_pem(pem)?;
+    single_cert_config(cert_chain, key_der, None)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls_acme::futures_rustls::pki_types::{CertificateDer, ServerName};
+    use rustls_acme::futures_rustls::rustls::{
+        ClientConfig, ClientConnection, RootCertStore, ServerConnection,
+    };
+    use std::convert::TryFrom;
+
+    /// Self-signed certificate, serialized once as signatures differ between serializations.
+    struct Generated {
+        der: CertificateDer<'static>,
+        cert_pem: String,
+        key_pem: String,
+    }
+
+    fn generate() -> Generated {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_pem = cert.serialize_pem().unwrap();
+        let der = rustls_pemfile::certs(&mut cert_pem.as_bytes()).unwrap();
+        Generated {
+            der: CertificateDer::from(der[0].clone()),
+            cert_pem,
+            key_pem: cert.serialize_private_key_pem(),
+        }
+    }
+
+    /// Certificate presented in a handshake with a client trusting `roots`.
+    fn served(cert: &ReloadableCert, roots: &[&Generated]) -> CertificateDer<'static> {
+        let mut store = RootCertStore::empty();
+        for root in roots {
+            store.add(root.der.clone()).unwrap();
+        }
+        let config = ClientConfig::builder()
+            .with_root_certificates(store)
+            .with_no_client_auth();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut client = ClientConnection::new(Arc::new(config), server_name).unwrap();
+        let mut server = ServerConnection::new(cert.server_config()).unwrap();
+        while client.is_handshaking() || server.is_handshaking() {
+            let mut buf = Vec::new();
+            client.write_tls(&mut buf).unwrap();
+            server.read_tls(&mut buf.as_slice()).unwrap();
+            server.process_new_packets().unwrap();
+            buf.clear();
+            server.write_tls(&mut buf).unwrap();
+            client.read_tls(&mut buf.as_slice()).unwrap();
+            client.process_new_packets().unwrap();
+        }
+        client.peer_certificates().unwrap()[0].clone().into_owned()
+    }
+
+    struct Files {
+        cert: PathBuf,
+        key: PathBuf,
+    }
+
+    impl Files {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir();
+            let name = format!("{}-{}", name, std::process::id());
+            Files {
+                cert: dir.join(format!("{}-cert.pem", name)),
+                key: dir.join(format!("{}-key.pem", name)),
+            }
+        }
+        fn write(&self, cert: &Generated, key: &Generated) {
+            fs::write(&self.cert, &cert.cert_pem).unwrap();
+            fs::write(&self.key, &key.key_pem).unwrap();
+        }
+    }
+
+    impl Drop for Files {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.cert);
+            let _ = fs::remove_file(&self.key);
+        }
+    }
+
+    #[test]
+    fn reload_new_certificate() {
+        let (first, second) = (generate(), generate());
+        let files = Files::new("reload-new");
+        files.write(&first, &first);
+        let cert = ReloadableCert::from_files(&files.cert, &files.key).unwrap();
+        assert_eq!(served(&cert, &[&first, &second]), first.der);
+        files.write(&second, &second);
+        assert_eq!(served(&cert, &[&first, &second]), first.der);
+        cert.reload().unwrap();
+        assert_eq!(served(&cert, &[&first, &second]), second.der);
+        assert_eq!(served(&cert.clone(), &[&second]), second.der);
+    }
+
+    #[test]
+    fn reload_mismatched_key() {
+        let (first, second) = (generate(), generate());
+        let files = Files::new("reload-mismatch");
+        files.write(&first, &first);
+        let cert = ReloadableCert::from_files(&files.cert, &files.key).unwrap();
+        files.write(&second, &first);
+        let err = cert.reload().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(served(&cert, &[&first, &second]), first.der);
+        fs::remove_file(&files.key).unwrap();
+        assert_eq!(cert.reload().unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(served(&cert, &[&first, &second]), first.der);
+    }
+}
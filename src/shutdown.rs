@@ -0,0 +1,174 @@
+use async_io::Timer;
+use futures::channel::oneshot;
+use futures::future::Shared;
+use futures::prelude::*;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+struct ShutdownInner {
+    sender: Mutex<Option<oneshot::Sender<Instant>>>,
+    receiver: Shared<oneshot::Receiver<Instant>>,
+}
+
+/// Trigger for gracefully shutting down all incoming streams a corresponding [ShutdownSignal] is
+/// attached to.
+///
+/// Attaching a signal to a [TcpIncoming](crate::TcpIncoming) also attaches it to the streams
+/// created from it using the chaining methods (e.g. `.tls(...)`, `.http()`).
+/// ```no_run
+/// # use async_web_server::{Shutdown, TcpIncoming};
+/// # use std::time::Duration;
+/// let shutdown = Shutdown::new();
+/// let incoming = TcpIncoming::bind(([0, 0, 0, 0], 80))?
+///     .graceful_shutdown(shutdown.signal())
+///     .http();
+/// // ...
+/// shutdown.trigger(Duration::from_secs(30));
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct Shutdown {
+    inner: Arc<ShutdownInner>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, receiver) = oneshot::channel();
+        Self {
+            inner: Arc::new(ShutdownInner {
+                sender: Mutex::new(Some(sender)),
+                receiver: receiver.shared(),
+            }),
+        }
+    }
+    /// Create a signal to attach to incoming streams.
+    pub fn signal(&self) -> ShutdownSignal {
+        ShutdownSignal::new(self.inner.clone())
+    }
+    /// Stop accepting new connections and requests. Pending work (e.g. TLS handshakes or
+    /// request heads being received) is abandoned after the grace period, after which all
+    /// streams the signal is attached to terminate. Triggering more than once has no effect.
+    pub fn trigger(&self, grace: Duration) {
+        if let Some(sender) = self.inner.sender.lock().unwrap().take() {
+            let _ = sender.send(Instant::now() + grace);
+        }
+    }
+    pub fn is_triggered(&self) -> bool {
+        self.inner.sender.lock().unwrap().is_none()
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum ShutdownState {
+    Running,
+    Draining,
+    Expired,
+}
+
+/// Signal obtained from [Shutdown::signal]. Resolves as a future once shutdown is triggered.
+pub struct ShutdownSignal {
+    inner: Arc<ShutdownInner>,
+    triggered: Option<Shared<oneshot::Receiver<Instant>>>,
+    deadline: Option<Timer>,
+}
+
+impl ShutdownSignal {
+    fn new(inner: Arc<ShutdownInner>) -> Self {
+        Self {
+            triggered: Some(inner.receiver.clone()),
+            inner,
+            deadline: None,
+        }
+    }
+    pub(crate) fn is_triggered(&self) -> bool {
+        self.inner.sender.lock().unwrap().is_none()
+    }
+    pub(crate) fn poll_state(&mut self, cx: &mut Context<'_>) -> ShutdownState {
+        if let Some(triggered) = &mut self.triggered {
+            match triggered.poll_unpin(cx) {
+                Poll::Ready(Ok(deadline)) => {
+                    self.triggered.take();
+                    self.deadline = Some(Timer::at(deadline));
+                }
+                // The sender is kept alive by the shared state until it is used.
+                Poll::Ready(Err(oneshot::Canceled)) => unreachable!(),
+                Poll::Pending => return ShutdownState::Running,
+            }
+        }
+        match self.deadline.as_mut().unwrap().poll_unpin(cx) {
+            Poll::Ready(_) => ShutdownState::Expired,
+            Poll::Pending => ShutdownState::Draining,
+        }
+    }
+}
+
+impl Clone for ShutdownSignal {
+    fn clone(&self) -> Self {
+        Self::new(self.inner.clone())
+    }
+}
+
+impl Future for ShutdownSignal {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.poll_state(cx) {
+            ShutdownState::Running => Poll::Pending,
+            ShutdownState::Draining | ShutdownState::Expired => Poll::Ready(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::task::noop_waker_ref;
+
+    fn state(signal: &mut ShutdownSignal) -> ShutdownState {
+        signal.poll_state(&mut Context::from_waker(noop_waker_ref()))
+    }
+
+    #[test]
+    fn state_transitions() {
+        let shutdown = Shutdown::new();
+        let mut signal = shutdown.signal();
+        assert_eq!(state(&mut signal), ShutdownState::Running);
+        assert!(!shutdown.is_triggered());
+
+        shutdown.trigger(Duration::from_millis(50));
+        assert!(shutdown.is_triggered());
+        assert!(signal.is_triggered());
+        assert_eq!(state(&mut signal), ShutdownState::Draining);
+        assert_eq!(state(&mut signal.clone()), ShutdownState::Draining);
+
+        block_on(Timer::after(Duration::from_millis(100)));
+        assert_eq!(state(&mut signal), ShutdownState::Expired);
+        assert_eq!(state(&mut shutdown.signal()), ShutdownState::Expired);
+    }
+
+    #[test]
+    fn trigger_once() {
+        let shutdown = Shutdown::new();
+        let mut signal = shutdown.signal();
+        shutdown.trigger(Duration::from_millis(50));
+        shutdown.trigger(Duration::from_secs(3600));
+        block_on(Timer::after(Duration::from_millis(100)));
+        assert_eq!(state(&mut signal), ShutdownState::Expired);
+    }
+
+    #[test]
+    fn signal_resolves_on_trigger() {
+        let shutdown = Shutdown::new();
+        let signal = shutdown.signal();
+        shutdown.trigger(Duration::from_secs(3600));
+        block_on(signal);
+    }
+}
@@ -0,0 +1,236 @@
+use crate::h1::HttpIncoming;
+use crate::shutdown::ShutdownState;
+use crate::{ShutdownSignal, TcpIncoming, TcpOrTlsStream, TcpStream, TlsIncoming};
+use async_io::Timer;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::future::{select, Either};
+use futures::prelude::*;
+use futures::stream::{FusedStream, FuturesUnordered};
+use rustls_acme::futures_rustls::rustls::server::ClientHello;
+use rustls_acme::futures_rustls::rustls::ServerConfig;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Content type of a TLS handshake record, the first byte sent by a TLS client.
+const TLS_HANDSHAKE: u8 = 0x16;
+
+type Sniff = Pin<Box<dyn Future<Output = io::Result<(TcpStream, bool)>> + Send>>;
+
+/// Accepts TLS and plaintext connections on the same listener by peeking at the first byte sent
+/// by the client, see [TcpIncoming::sniff_tls_with_config].
+pub struct SniffingIncoming<F: FnMut(&ClientHello) -> Arc<ServerConfig>> {
+    tcp_incoming: Option<TcpIncoming>,
+    sniffing: FuturesUnordered<Sniff>,
+    tls_sender: Option<UnboundedSender<TcpStream>>,
+    tls_incoming: TlsIncoming<F, TcpStream, UnboundedReceiver<TcpStream>>,
+    sniff_timeout: Duration,
+    shutdown: Option<ShutdownSignal>,
+}
+
+impl<F: FnMut(&ClientHello) -> Arc<ServerConfig>> SniffingIncoming<F> {
+    pub fn new(tcp_incoming: TcpIncoming, f: F) -> Self {
+        let (tls_sender, tls_receiver) = unbounded();
+        let shutdown = tcp_incoming.shutdown.clone();
+        SniffingIncoming {
+            tcp_incoming: Some(tcp_incoming),
+            sniffing: FuturesUnordered::new(),
+            tls_sender: Some(tls_sender),
+            tls_incoming: TlsIncoming::with_transport(tls_receiver, f)
+                .with_optional_shutdown(shutdown.clone()),
+            sniff_timeout: Duration::from_secs(5),
+            shutdown,
+        }
+    }
+    /// Limit the time for the client to send its first byte (5 seconds by default).
+    pub fn sniff_timeout(mut self, timeout: Duration) -> Self {
+        self.sniff_timeout = timeout;
+        self
+    }
+    /// Limit the number of concurrent pending TLS handshakes, see
+    /// [TlsIncoming::max_pending_handshakes].
+    pub fn max_pending_handshakes(mut self, max: usize) -> Self {
+        self.tls_incoming = self.tls_incoming.max_pending_handshakes(max);
+        self
+    }
+    /// Advertise protocols via ALPN for TLS connections, see [TlsIncoming::alpn_protocols].
+    pub fn alpn_protocols(mut self, protocols: impl IntoIterator<Item = impl AsRef<[u8]>>) -> Self {
+        self.tls_incoming = self.tls_incoming.alpn_protocols(protocols);
+        self
+    }
+    /// Size of the TLS session cache, see [TlsIncoming::session_cache_size].
+    pub fn session_cache_size(mut self, size: usize) -> Self {
+        self.tls_incoming = self.tls_incoming.session_cache_size(size);
+        self
+    }
+    /// Issue stateless TLS session tickets, see [TlsIncoming::ticket_rotation].
+    pub fn ticket_rotation(mut self, interval: Duration) -> Self {
+        self.tls_incoming = self.tls_incoming.ticket_rotation(interval);
+        self
+    }
+    /// Stop accepting connections once shutdown is triggered and abandon handshakes still pending
+    /// at the deadline, see [Shutdown](crate::Shutdown).
+    pub fn graceful_shutdown(mut self, signal: ShutdownSignal) -> Self {
+        self.tls_incoming = self.tls_incoming.graceful_shutdown(signal.clone());
+        self.shutdown = Some(signal);
+        self
+    }
+    /// Plaintext requests can be redirected to HTTPS using [HttpIncoming::redirect_plaintext].
+    pub fn http(self) -> HttpIncoming<TcpOrTlsStream, Self> {
+        let shutdown = self.shutdown.clone();
+        HttpIncoming::new(self).with_optional_shutdown(shutdown)
+    }
+}
+
+impl<F: FnMut(&ClientHello) -> Arc<ServerConfig>> Unpin for SniffingIncoming<F> {}
+
+impl<F: FnMut(&ClientHello) -> Arc<ServerConfig>> Stream for SniffingIncoming<F> {
+    type Item = TcpOrTlsStream;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Some(shutdown) = &mut this.shutdown {
+            match shutdown.poll_state(cx) {
+                ShutdownState::Running => {}
+                ShutdownState::Draining => drop(this.tcp_incoming.take()),
+                ShutdownState::Expired => {
+                    drop(this.tcp_incoming.take());
+                    this.sniffing.clear();
+                }
+            }
+        }
+        loop {
+            if let Poll::Ready(Some(tls_stream)) = this.tls_incoming.poll_next_unpin(cx) {
+                return Poll::Ready(Some(TcpOrTlsStream::Tls(tls_stream)));
+            }
+            match this.sniffing.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok((tcp_stream, true)))) => {
+                    if let Some(tls_sender) = &this.tls_sender {
+                        let _ = tls_sender.unbounded_send(tcp_stream);
+                    }
+                }
+                Poll::Ready(Some(Ok((tcp_stream, false)))) => {
+                    return Poll::Ready(Some(TcpOrTlsStream::Tcp(tcp_stream)))
+                }
+                Poll::Ready(Some(Err(err))) => log::debug!("tls sniffing error: {:?}", err),
+                Poll::Ready(None) | Poll::Pending => match &mut this.tcp_incoming {
+                    // Let the TLS stream terminate once no more connections can be passed on.
+                    None if this.sniffing.is_empty() && this.tls_sender.is_some() => {
+                        drop(this.tls_sender.take())
+                    }
+                    None => match this.is_terminated() {
+                        true => return Poll::Ready(None),
+                        false => return Poll::Pending,
+                    },
+                    Some(tcp_incoming) => match tcp_incoming.poll_next_unpin(cx) {
+                        Poll::Ready(Some(tcp_stream)) => {
+                            let timeout = this.sniff_timeout;
+                            this.sniffing.push(Box::pin(async move {
+                                let mut byte = [0u8];
+                                let peeked = {
+                                    let peek = Box::pin(tcp_stream.peek(&mut byte));
+                                    match select(peek, Timer::after(timeout)).await {
+                                        Either::Left((result, _)) => result,
+                                        Either::Right(_) => Err(io::ErrorKind::TimedOut.into()),
+                                    }
+                                };
+                                match peeked? {
+                                    0 => Err(io::ErrorKind::UnexpectedEof.into()),
+                                    _ => Ok((tcp_stream, byte[0] == TLS_HANDSHAKE)),
+                                }
+                            }))
+                        }
+                        Poll::Ready(None) => drop(this.tcp_incoming.take()),
+                        Poll::Pending => return Poll::Pending,
+                    },
+                },
+            }
+        }
+    }
+}
+
+impl<F: FnMut(&ClientHello) -> Arc<ServerConfig>> FusedStream for SniffingIncoming<F> {
+    fn is_terminated(&self) -> bool {
+        self.tcp_incoming.is_none()
+            && self.sniffing.is_terminated()
+            && self.tls_incoming.is_terminated()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use rustls_acme::futures_rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName};
+    use rustls_acme::futures_rustls::rustls::{ClientConfig, RootCertStore};
+    use rustls_acme::futures_rustls::TlsConnector;
+    use std::convert::TryFrom;
+    use std::net::SocketAddr;
+
+    fn incoming(
+        timeout: Duration,
+    ) -> (
+        SniffingIncoming<impl FnMut(&ClientHello) -> Arc<ServerConfig>>,
+        SocketAddr,
+        TlsConnector,
+    ) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_der = CertificateDer::from(cert.serialize_der().unwrap());
+        let key_der = PrivatePkcs8KeyDer::from(cert.serialize_private_key_der());
+        let tcp = TcpIncoming::bind(([127, 0, 0, 1], 0)).unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let incoming = tcp
+            .sniff_tls(vec![cert_der.clone()], key_der.into())
+            .unwrap()
+            .sniff_timeout(timeout);
+        let mut roots = RootCertStore::empty();
+        roots.add(cert_der).unwrap();
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        (incoming, addr, TlsConnector::from(Arc::new(config)))
+    }
+
+    /// Next connection, unless none is accepted before a short timeout.
+    async fn next<S: Stream + Unpin>(incoming: &mut S) -> Option<S::Item> {
+        let timer = Timer::after(Duration::from_millis(300));
+        match select(incoming.next(), timer).await {
+            Either::Left((item, _)) => item,
+            Either::Right(_) => None,
+        }
+    }
+
+    #[test]
+    fn plaintext_and_tls() {
+        let (mut incoming, addr, connector) = incoming(Duration::from_secs(5));
+        block_on(async {
+            let mut plain = async_net::TcpStream::connect(addr).await.unwrap();
+            plain.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+            assert!(matches!(
+                next(&mut incoming).await,
+                Some(TcpOrTlsStream::Tcp(_))
+            ));
+            let tcp = async_net::TcpStream::connect(addr).await.unwrap();
+            let server_name = ServerName::try_from("localhost").unwrap();
+            let client = connector.connect(server_name, tcp);
+            let (client, server) = future::join(client, next(&mut incoming)).await;
+            client.unwrap();
+            assert!(matches!(server, Some(TcpOrTlsStream::Tls(_))));
+        });
+    }
+
+    #[test]
+    fn sniff_timeout() {
+        let (mut incoming, addr, _) = incoming(Duration::from_millis(50));
+        let mut silent = std::net::TcpStream::connect(addr).unwrap();
+        block_on(async { assert!(next(&mut incoming).await.is_none()) });
+        // The connection is dropped once the client exceeded the timeout.
+        let mut buf = [0u8; 1];
+        assert!(matches!(
+            std::io::Read::read(&mut silent, &mut buf),
+            Ok(0) | Err(_)
+        ));
+    }
+}
@@ -1,6 +1,10 @@
 use crate::h1::HttpIncoming;
-use crate::tls::TlsIncoming;
-use crate::{AcmeIncoming, TcpOrTlsIncoming};
+use crate::shutdown::ShutdownState;
+use crate::tls::{single_cert_config, TlsIncoming};
+use crate::{
+    AcmeIncoming, CertStore, ClientAuth, ClientCertificate, HasTlsInfo, Http2Incoming,
+    ProxyIncoming, ReloadableCert, ShutdownSignal, SniffingIncoming, TcpOrTlsIncoming, TlsInfo,
+};
 use async_io::{Async, ReadableOwned};
 use futures::prelude::*;
 use futures::stream::FusedStream;
@@ -10,26 +14,192 @@ use rustls_acme::futures_rustls::pki_types::{CertificateDer, PrivateKeyDer};
 use rustls_acme::futures_rustls::rustls::server::ClientHello;
 use rustls_acme::futures_rustls::rustls::ServerConfig;
 use rustls_acme::AcmeConfig;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::fmt::Debug;
 use std::io;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Deref;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
 use std::path::Path;
 use std::pin::Pin;
-use std::sync::Arc;
-use std::task::{Context, Poll};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
 
-pub type TcpStream = async_net::TcpStream;
+/// Stream accepted by [TcpIncoming], dereferencing to [async_net::TcpStream]. Clones refer to
+/// the same connection, which counts towards the limits of the listener until all of them are
+/// dropped.
+#[derive(Clone, Debug)]
+pub struct TcpStream {
+    stream: async_net::TcpStream,
+    /// Held for releasing the connection slot on drop.
+    _live: Option<Arc<LiveConnection>>,
+}
+
+impl From<async_net::TcpStream> for TcpStream {
+    fn from(stream: async_net::TcpStream) -> Self {
+        TcpStream {
+            stream,
+            _live: None,
+        }
+    }
+}
+
+impl Deref for TcpStream {
+    type Target = async_net::TcpStream;
+
+    fn deref(&self) -> &Self::Target {
+        &self.stream
+    }
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_close(cx)
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for TcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+pub trait PeerAddr {
+    /// Address of the remote peer of the underlying connection.
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+    /// Local address of the underlying connection.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl PeerAddr for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+}
+
+impl HasTlsInfo for TcpStream {
+    fn tls_info(&self) -> Option<TlsInfo> {
+        None
+    }
+}
+
+impl ClientCertificate for TcpStream {
+    fn peer_certificates(&self) -> Option<&[CertificateDer<'_>]> {
+        None
+    }
+}
 
 pub struct TcpIncoming {
     listener: Arc<Async<std::net::TcpListener>>,
     readable: Pin<Box<ReadableOwned<std::net::TcpListener>>>,
+    pub(crate) shutdown: Option<ShutdownSignal>,
+    terminated: bool,
+    limits: ConnectionLimits,
+    stream_options: StreamOptions,
 }
 
 impl TcpIncoming {
+    /// Configure socket options before binding.
+    /// ```no_run
+    /// # use async_web_server::TcpIncoming;
+    /// let incoming = TcpIncoming::builder()
+    ///     .reuse_port(true)
+    ///     .backlog(4096)
+    ///     .nodelay(true)
+    ///     .bind(([0, 0, 0, 0], 8080))?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn builder() -> TcpIncomingBuilder {
+        TcpIncomingBuilder::default()
+    }
     pub fn bind(addr: impl Into<SocketAddr>) -> io::Result<Self> {
-        let listener = Arc::new(Async::<std::net::TcpListener>::bind(addr)?);
+        Ok(Self::from_async(Async::<std::net::TcpListener>::bind(
+            addr,
+        )?))
+    }
+    /// Adopt an already bound listener, e.g. one inherited from a parent process.
+    pub fn from_std(listener: std::net::TcpListener) -> io::Result<Self> {
+        Ok(Self::from_async(Async::new(listener)?))
+    }
+    /// Adopt a listening socket passed as file descriptor, e.g. during a restart handing over
+    /// listeners to a new process. See [ListenFds](crate::ListenFds) for systemd socket activation.
+    #[cfg(unix)]
+    pub fn from_fd(fd: OwnedFd) -> io::Result<Self> {
+        let raw = fd.as_raw_fd();
+        match crate::unix::socket_domain(raw)? {
+            libc::AF_INET | libc::AF_INET6 if crate::unix::is_listening_stream(raw)? => {
+                Self::from_std(fd.into())
+            }
+            libc::AF_INET | libc::AF_INET6 => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a listening tcp socket",
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a tcp socket",
+            )),
+        }
+    }
+    fn from_async(listener: Async<std::net::TcpListener>) -> Self {
+        let listener = Arc::new(listener);
         let readable = Box::pin(listener.clone().readable_owned());
-        Ok(Self { listener, readable })
+        Self {
+            listener,
+            readable,
+            shutdown: None,
+            terminated: false,
+            limits: ConnectionLimits::default(),
+            stream_options: StreamOptions::default(),
+        }
+    }
+    /// Limit the number of live connections (unlimited by default). A connection counts as live
+    /// until all handles to its stream are dropped. While the limit is reached the listener is not
+    /// polled, leaving further connections in the backlog.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.limits.max = Some(max);
+        self
+    }
+    /// Limit the number of live connections per peer IP address (unlimited by default).
+    /// Connections exceeding the limit are closed immediately after accepting them.
+    pub fn max_connections_per_ip(mut self, max: usize) -> Self {
+        self.limits.max_per_ip = Some(max);
+        self
+    }
+    /// Stop accepting connections once shutdown is triggered, see [Shutdown](crate::Shutdown).
+    /// The signal is passed on to streams created using the chaining methods.
+    pub fn graceful_shutdown(mut self, signal: ShutdownSignal) -> Self {
+        self.shutdown = Some(signal);
+        self
     }
     pub fn tls_with_config<F: FnMut(&ClientHello) -> Arc<ServerConfig>>(
         self,
@@ -45,10 +215,62 @@ impl TcpIncoming {
         TlsIncoming<impl FnMut(&ClientHello) -> Arc<ServerConfig>>,
         rustls_acme::futures_rustls::rustls::Error,
     > {
-        let config = ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(cert_chain, key_der)?;
-        let config = Arc::new(config);
+        let config = single_cert_config(cert_chain, key_der, None)?;
+        Ok(TlsIncoming::new(self, move |_| config.clone()))
+    }
+    /// Serve a certificate per domain, selected by the server name sent by the client, see
+    /// [CertStore].
+    pub fn tls_with_store(
+        self,
+        store: CertStore,
+    ) -> TlsIncoming<impl FnMut(&ClientHello) -> Arc<ServerConfig>> {
+        let config = store.server_config();
+        TlsIncoming::new(self, move |_| config.clone())
+    }
+    /// Serve a certificate that can be replaced while the listener is running, see
+    /// [ReloadableCert].
+    pub fn tls_reloadable(
+        self,
+        cert: ReloadableCert,
+    ) -> TlsIncoming<impl FnMut(&ClientHello) -> Arc<ServerConfig>> {
+        TlsIncoming::new(self, move |_| cert.server_config())
+    }
+    /// Serve HTTP/2 without TLS to clients with prior knowledge (h2c), see [Http2Incoming].
+    pub fn h2c(self) -> Http2Incoming<TcpStream, Self> {
+        let shutdown = self.shutdown.clone();
+        Http2Incoming::new(self).with_optional_shutdown(shutdown)
+    }
+    /// Accept both TLS and plaintext connections on this listener, distinguished by the first byte
+    /// sent by the client, see [SniffingIncoming].
+    pub fn sniff_tls_with_config<F: FnMut(&ClientHello) -> Arc<ServerConfig>>(
+        self,
+        f: F,
+    ) -> SniffingIncoming<F> {
+        SniffingIncoming::new(self, f)
+    }
+    pub fn sniff_tls(
+        self,
+        cert_chain: Vec<CertificateDer<'static>>,
+        key_der: PrivateKeyDer<'static>,
+    ) -> Result<
+        SniffingIncoming<impl FnMut(&ClientHello) -> Arc<ServerConfig>>,
+        rustls_acme::futures_rustls::rustls::Error,
+    > {
+        let config = single_cert_config(cert_chain, key_der, None)?;
+        Ok(SniffingIncoming::new(self, move |_| config.clone()))
+    }
+    /// Like [Self::tls], but authenticating clients by their certificates, see [ClientAuth] and
+    /// [ClientCertificate](crate::ClientCertificate).
+    pub fn tls_with_client_auth(
+        self,
+        cert_chain: Vec<CertificateDer<'static>>,
+        key_der: PrivateKeyDer<'static>,
+        client_auth: &ClientAuth,
+    ) -> Result<
+        TlsIncoming<impl FnMut(&ClientHello) -> Arc<ServerConfig>>,
+        rustls_acme::futures_rustls::rustls::Error,
+    > {
+        let config = single_cert_config(cert_chain, key_der, Some(client_auth))?;
         Ok(TlsIncoming::new(self, move |_| config.clone()))
     }
     pub fn tls_acme<EC: Debug, EA: Debug>(
@@ -71,13 +293,19 @@ impl TcpIncoming {
             .directory_lets_encrypt(production);
         self.tls_acme(config)
     }
+    /// Expect a PROXY protocol header on each connection, accepting only connections from proxies
+    /// added using [ProxyIncoming::trust].
+    pub fn proxy_protocol(self) -> ProxyIncoming {
+        ProxyIncoming::new(self)
+    }
     pub fn or_tls(self) -> TcpOrTlsIncoming {
         let mut tcp_or_tls = TcpOrTlsIncoming::new();
         tcp_or_tls.push(self);
         tcp_or_tls
     }
     pub fn http(self) -> HttpIncoming<TcpStream, Self> {
-        HttpIncoming::new(self)
+        let shutdown = self.shutdown.clone();
+        HttpIncoming::new(self).with_optional_shutdown(shutdown)
     }
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         self.listener.get_ref().local_addr()
@@ -88,11 +316,36 @@ impl Stream for TcpIncoming {
     type Item = TcpStream;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(shutdown) = &mut self.shutdown {
+            if shutdown.poll_state(cx) != ShutdownState::Running {
+                self.terminated = true;
+            }
+        }
+        if self.terminated {
+            return Poll::Ready(None);
+        }
+        if self.limits.poll_capacity(cx).is_pending() {
+            return Poll::Pending;
+        }
         let listener = self.listener.clone();
         loop {
             match Box::pin(listener.accept()).poll_unpin(cx) {
                 Poll::Ready(result) => match result {
-                    Ok((stream, _)) => return Poll::Ready(Some(stream.into())),
+                    Ok((stream, addr)) => {
+                        if let Err(err) = self.stream_options.apply(stream.get_ref()) {
+                            log::debug!("tcp socket option error: {:?}", err);
+                        }
+                        match self.limits.admit(addr.ip()) {
+                            Ok(live) => {
+                                let stream = async_net::TcpStream::from(stream);
+                                return Poll::Ready(Some(TcpStream {
+                                    stream,
+                                    _live: live,
+                                }));
+                            }
+                            Err(()) => log::debug!("tcp connection limit exceeded for {}", addr),
+                        }
+                    }
                     Err(err) => log::debug!("tcp accept error: {:?}", err),
                 },
                 Poll::Pending => match self.readable.as_mut().poll(cx) {
@@ -104,8 +357,271 @@ impl Stream for TcpIncoming {
     }
 }
 
+#[cfg(unix)]
+impl AsRawFd for TcpIncoming {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
 impl FusedStream for TcpIncoming {
     fn is_terminated(&self) -> bool {
-        false
+        self.terminated
+    }
+}
+
+/// Builder for [TcpIncoming] with control over listener and accepted stream socket options,
+/// see [TcpIncoming::builder]. Options left unset keep the operating system defaults.
+pub struct TcpIncomingBuilder {
+    backlog: i32,
+    reuse_address: bool,
+    reuse_port: bool,
+    only_v6: Option<bool>,
+    stream_options: StreamOptions,
+}
+
+impl Default for TcpIncomingBuilder {
+    fn default() -> Self {
+        Self {
+            backlog: 128,
+            // Matches std::net::TcpListener::bind.
+            reuse_address: cfg!(unix),
+            reuse_port: false,
+            only_v6: None,
+            stream_options: StreamOptions::default(),
+        }
+    }
+}
+
+impl TcpIncomingBuilder {
+    /// Maximum length of the queue of pending connections (128 by default).
+    pub fn backlog(mut self, backlog: i32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+    /// Set `SO_REUSEADDR` (enabled by default on Unix, like [std::net::TcpListener::bind]).
+    pub fn reuse_address(mut self, enable: bool) -> Self {
+        self.reuse_address = enable;
+        self
+    }
+    /// Set `SO_REUSEPORT`, allowing multiple processes to bind the same port with the kernel
+    /// distributing connections between them.
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    pub fn reuse_port(mut self, enable: bool) -> Self {
+        self.reuse_port = enable;
+        self
+    }
+    /// Set `IPV6_V6ONLY` for IPv6 addresses, controlling whether IPv4 connections are accepted
+    /// as well.
+    pub fn only_v6(mut self, enable: bool) -> Self {
+        self.only_v6 = Some(enable);
+        self
+    }
+    /// Set `TCP_NODELAY` on accepted streams, disabling Nagle's algorithm.
+    pub fn nodelay(mut self, enable: bool) -> Self {
+        self.stream_options.nodelay = Some(enable);
+        self
+    }
+    /// Enable TCP keepalive on accepted streams, probing idle connections after `time`.
+    pub fn keepalive(mut self, time: Duration) -> Self {
+        self.stream_options.keepalive = Some(time);
+        self
+    }
+    /// Set `SO_RCVBUF` on accepted streams.
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.stream_options.recv_buffer_size = Some(size);
+        self
+    }
+    /// Set `SO_SNDBUF` on accepted streams.
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.stream_options.send_buffer_size = Some(size);
+        self
+    }
+    pub fn bind(self, addr: impl Into<SocketAddr>) -> io::Result<TcpIncoming> {
+        let addr = addr.into();
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_reuse_address(self.reuse_address)?;
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        if self.reuse_port {
+            socket.set_reuse_port(true)?;
+        }
+        if let (Some(only_v6), SocketAddr::V6(_)) = (self.only_v6, addr) {
+            socket.set_only_v6(only_v6)?;
+        }
+        socket.bind(&addr.into())?;
+        socket.listen(self.backlog)?;
+        let mut incoming = TcpIncoming::from_std(socket.into())?;
+        incoming.stream_options = self.stream_options;
+        Ok(incoming)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct StreamOptions {
+    nodelay: Option<bool>,
+    keepalive: Option<Duration>,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+}
+
+impl StreamOptions {
+    fn apply(&self, stream: &std::net::TcpStream) -> io::Result<()> {
+        let socket = SockRef::from(stream);
+        if let Some(nodelay) = self.nodelay {
+            socket.set_nodelay(nodelay)?;
+        }
+        if let Some(time) = self.keepalive {
+            socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct ConnectionLimits {
+    max: Option<usize>,
+    max_per_ip: Option<usize>,
+    state: Arc<Mutex<LimitState>>,
+}
+
+#[derive(Debug, Default)]
+struct LimitState {
+    count: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    /// Listener waiting for a connection to close while the limit is reached.
+    waker: Option<Waker>,
+}
+
+impl ConnectionLimits {
+    fn poll_capacity(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let max = match self.max {
+            Some(max) => max,
+            None => return Poll::Ready(()),
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.count < max {
+            return Poll::Ready(());
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+    /// Count a new connection from `ip`, unless that exceeds [TcpIncoming::max_connections_per_ip].
+    fn admit(&mut self, ip: IpAddr) -> Result<Option<Arc<LiveConnection>>, ()> {
+        if self.max.is_none() && self.max_per_ip.is_none() {
+            return Ok(None);
+        }
+        let mut state = self.state.lock().unwrap();
+        let per_ip = state.per_ip.entry(ip).or_default();
+        if matches!(self.max_per_ip, Some(max_per_ip) if *per_ip >= max_per_ip) {
+            if *per_ip == 0 {
+                state.per_ip.remove(&ip);
+            }
+            return Err(());
+        }
+        *per_ip += 1;
+        state.count += 1;
+        Ok(Some(Arc::new(LiveConnection {
+            ip,
+            state: self.state.clone(),
+        })))
+    }
+}
+
+/// Shared by all clones of an admitted [TcpStream], releasing its slot when the last is dropped.
+#[derive(Debug)]
+struct LiveConnection {
+    ip: IpAddr,
+    state: Arc<Mutex<LimitState>>,
+}
+
+impl Drop for LiveConnection {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.count -= 1;
+        if let Entry::Occupied(mut per_ip) = state.per_ip.entry(self.ip) {
+            *per_ip.get_mut() -= 1;
+            if *per_ip.get() == 0 {
+                per_ip.remove();
+            }
+        }
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_io::Timer;
+    use futures::executor::block_on;
+    use futures::future::{select, Either};
+    use std::io::Read;
+
+    /// Next connection, unless none is accepted before a short timeout.
+    async fn accept(incoming: &mut TcpIncoming) -> Option<TcpStream> {
+        let timer = Timer::after(Duration::from_millis(200));
+        match select(incoming.next(), timer).await {
+            Either::Left((stream, _)) => stream,
+            Either::Right(_) => None,
+        }
+    }
+
+    fn connect(incoming: &TcpIncoming) -> std::net::TcpStream {
+        std::net::TcpStream::connect(incoming.local_addr().unwrap()).unwrap()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn from_fd() {
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let err = TcpIncoming::from_fd(udp.into()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let err = TcpIncoming::from_fd(stream.into()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(TcpIncoming::from_fd(listener.into()).is_ok());
+    }
+
+    #[test]
+    fn max_connections() {
+        let mut incoming = TcpIncoming::bind(([127, 0, 0, 1], 0))
+            .unwrap()
+            .max_connections(1);
+        let _first = connect(&incoming);
+        let _second = connect(&incoming);
+        block_on(async {
+            let first = accept(&mut incoming).await.unwrap();
+            let clone = first.clone();
+            assert!(accept(&mut incoming).await.is_none());
+            drop(first);
+            assert!(accept(&mut incoming).await.is_none());
+            drop(clone);
+            assert!(accept(&mut incoming).await.is_some());
+        });
+    }
+
+    #[test]
+    fn max_connections_per_ip() {
+        let mut incoming = TcpIncoming::bind(([127, 0, 0, 1], 0))
+            .unwrap()
+            .max_connections_per_ip(1);
+        let _first = connect(&incoming);
+        let mut second = connect(&incoming);
+        block_on(async {
+            let first = accept(&mut incoming).await.unwrap();
+            assert!(accept(&mut incoming).await.is_none());
+            assert!(matches!(second.read(&mut [0u8; 1]), Ok(0) | Err(_)));
+            drop(first);
+            let _third = connect(&incoming);
+            assert!(accept(&mut incoming).await.is_some());
+        });
     }
 }
@@ -1,7 +1,12 @@
-use crate::{HttpIncoming, TcpStream, TlsStream};
+use crate::{ClientCertificate, HasTlsInfo, HttpIncoming, PeerAddr, TcpStream, TlsInfo, TlsStream};
+#[cfg(unix)]
+use crate::{PeerCred, PeerCredentials, UnixStream};
 use futures::prelude::*;
 use futures::stream::{FusedStream, SelectAll};
 use futures::StreamExt;
+use rustls_acme::futures_rustls::pki_types::CertificateDer;
+use std::io;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
@@ -9,9 +14,30 @@ pub trait IsTls {
     fn is_tls(&self) -> bool;
 }
 
+impl IsTls for TcpStream {
+    fn is_tls(&self) -> bool {
+        false
+    }
+}
+
+impl<IO> IsTls for TlsStream<IO> {
+    fn is_tls(&self) -> bool {
+        true
+    }
+}
+
+/// Stream yielded by [TcpOrTlsIncoming]. Streams over Unix domain sockets have no internet
+/// address, so [PeerAddr] fails for them with [io::ErrorKind::Unsupported]; their peer is
+/// identified by [PeerCred](crate::PeerCred) instead.
+#[allow(clippy::large_enum_variant)]
+#[non_exhaustive]
 pub enum TcpOrTlsStream {
     Tcp(TcpStream),
     Tls(TlsStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(unix)]
+    UnixTls(TlsStream<UnixStream>),
 }
 
 impl IsTls for TcpOrTlsStream {
@@ -19,6 +45,77 @@ impl IsTls for TcpOrTlsStream {
         match self {
             Self::Tcp(_) => false,
             Self::Tls(_) => true,
+            #[cfg(unix)]
+            Self::Unix(_) => false,
+            #[cfg(unix)]
+            Self::UnixTls(_) => true,
+        }
+    }
+}
+
+impl PeerAddr for TcpOrTlsStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Tcp(tcp) => PeerAddr::peer_addr(tcp),
+            Self::Tls(tls) => tls.peer_addr(),
+            #[cfg(unix)]
+            Self::Unix(_) | Self::UnixTls(_) => Err(no_socket_addr()),
+        }
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Tcp(tcp) => PeerAddr::local_addr(tcp),
+            Self::Tls(tls) => tls.local_addr(),
+            #[cfg(unix)]
+            Self::Unix(_) | Self::UnixTls(_) => Err(no_socket_addr()),
+        }
+    }
+}
+
+impl HasTlsInfo for TcpOrTlsStream {
+    fn tls_info(&self) -> Option<TlsInfo> {
+        match self {
+            Self::Tcp(_) => None,
+            Self::Tls(tls) => tls.tls_info(),
+            #[cfg(unix)]
+            Self::Unix(_) => None,
+            #[cfg(unix)]
+            Self::UnixTls(tls) => tls.tls_info(),
+        }
+    }
+}
+
+impl ClientCertificate for TcpOrTlsStream {
+    fn peer_certificates(&self) -> Option<&[CertificateDer<'_>]> {
+        match self {
+            Self::Tcp(_) => None,
+            Self::Tls(tls) => tls.peer_certificates(),
+            #[cfg(unix)]
+            Self::Unix(_) => None,
+            #[cfg(unix)]
+            Self::UnixTls(tls) => tls.peer_certificates(),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn no_socket_addr() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "unix domain socket has no internet address",
+    )
+}
+
+#[cfg(unix)]
+impl PeerCred for TcpOrTlsStream {
+    fn peer_cred(&self) -> io::Result<PeerCredentials> {
+        match self {
+            Self::Tcp(_) | Self::Tls(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "peer credentials are only available for unix domain sockets",
+            )),
+            Self::Unix(unix) => unix.peer_cred(),
+            Self::UnixTls(tls) => tls.peer_cred(),
         }
     }
 }
@@ -28,6 +125,7 @@ pub struct TcpOrTlsIncoming {
 }
 
 impl TcpOrTlsIncoming {
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         Self {
             incomings: SelectAll::new(),
@@ -41,7 +139,7 @@ impl TcpOrTlsIncoming {
             .push(Box::new(incoming.map(|stream| stream.into())))
     }
     pub fn merge(&mut self, other: Self) {
-        self.incomings.extend(other.incomings.into_iter())
+        self.incomings.extend(other.incomings)
     }
     pub fn http(self) -> HttpIncoming<TcpOrTlsStream, Self> {
         HttpIncoming::new(self)
@@ -73,6 +171,10 @@ impl AsyncRead for TcpOrTlsStream {
         match self.get_mut() {
             TcpOrTlsStream::Tcp(tcp) => Pin::new(tcp).poll_read(cx, buf),
             TcpOrTlsStream::Tls(tls) => Pin::new(tls).poll_read(cx, buf),
+            #[cfg(unix)]
+            TcpOrTlsStream::Unix(unix) => Pin::new(unix).poll_read(cx, buf),
+            #[cfg(unix)]
+            TcpOrTlsStream::UnixTls(tls) => Pin::new(tls).poll_read(cx, buf),
         }
     }
 }
@@ -86,6 +188,10 @@ impl AsyncWrite for TcpOrTlsStream {
         match self.get_mut() {
             TcpOrTlsStream::Tcp(tcp) => Pin::new(tcp).poll_write(cx, buf),
             TcpOrTlsStream::Tls(tls) => Pin::new(tls).poll_write(cx, buf),
+            #[cfg(unix)]
+            TcpOrTlsStream::Unix(unix) => Pin::new(unix).poll_write(cx, buf),
+            #[cfg(unix)]
+            TcpOrTlsStream::UnixTls(tls) => Pin::new(tls).poll_write(cx, buf),
         }
     }
 
@@ -93,6 +199,10 @@ impl AsyncWrite for TcpOrTlsStream {
         match self.get_mut() {
             TcpOrTlsStream::Tcp(tcp) => Pin::new(tcp).poll_flush(cx),
             TcpOrTlsStream::Tls(tls) => Pin::new(tls).poll_flush(cx),
+            #[cfg(unix)]
+            TcpOrTlsStream::Unix(unix) => Pin::new(unix).poll_flush(cx),
+            #[cfg(unix)]
+            TcpOrTlsStream::UnixTls(tls) => Pin::new(tls).poll_flush(cx),
         }
     }
 
@@ -100,6 +210,10 @@ impl AsyncWrite for TcpOrTlsStream {
         match self.get_mut() {
             TcpOrTlsStream::Tcp(tcp) => Pin::new(tcp).poll_close(cx),
             TcpOrTlsStream::Tls(tls) => Pin::new(tls).poll_close(cx),
+            #[cfg(unix)]
+            TcpOrTlsStream::Unix(unix) => Pin::new(unix).poll_close(cx),
+            #[cfg(unix)]
+            TcpOrTlsStream::UnixTls(tls) => Pin::new(tls).poll_close(cx),
         }
     }
 }
@@ -115,3 +229,17 @@ impl From<TlsStream> for TcpOrTlsStream {
         TcpOrTlsStream::Tls(value)
     }
 }
+
+#[cfg(unix)]
+impl From<UnixStream> for TcpOrTlsStream {
+    fn from(value: UnixStream) -> Self {
+        TcpOrTlsStream::Unix(value)
+    }
+}
+
+#[cfg(unix)]
+impl From<TlsStream<UnixStream>> for TcpOrTlsStream {
+    fn from(value: TlsStream<UnixStream>) -> Self {
+        TcpOrTlsStream::UnixTls(value)
+    }
+}
@@ -0,0 +1,422 @@
+use crate::h1::HttpIncoming;
+use crate::shutdown::ShutdownState;
+use crate::tls::single_cert_config;
+use crate::{ShutdownSignal, TcpOrTlsIncoming, TlsIncoming, TlsStream};
+use async_io::{Async, ReadableOwned};
+use futures::prelude::*;
+use futures::stream::FusedStream;
+use futures::FutureExt;
+use rustls_acme::futures_rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls_acme::futures_rustls::rustls::server::ClientHello;
+use rustls_acme::futures_rustls::rustls::ServerConfig;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+pub type UnixStream = async_net::unix::UnixStream;
+
+/// Credentials of the process on the other end of a Unix domain socket, captured when the
+/// connection was established.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// Only available on Linux and Android.
+    pub pid: Option<i32>,
+}
+
+pub trait PeerCred {
+    /// Credentials of the remote peer of the underlying Unix domain socket connection.
+    fn peer_cred(&self) -> io::Result<PeerCredentials>;
+}
+
+impl PeerCred for UnixStream {
+    fn peer_cred(&self) -> io::Result<PeerCredentials> {
+        peer_credentials(self.as_raw_fd())
+    }
+}
+
+impl<IO: PeerCred> PeerCred for TlsStream<IO> {
+    fn peer_cred(&self) -> io::Result<PeerCredentials> {
+        self.get_ref().0.peer_cred()
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_credentials(fd: RawFd) -> io::Result<PeerCredentials> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    match ret {
+        0 => Ok(PeerCredentials {
+            uid: cred.uid,
+            gid: cred.gid,
+            pid: Some(cred.pid),
+        }),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_credentials(fd: RawFd) -> io::Result<PeerCredentials> {
+    let (mut uid, mut gid) = (0, 0);
+    match unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } {
+        0 => Ok(PeerCredentials {
+            uid: uid as u32,
+            gid: gid as u32,
+            pid: None,
+        }),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+pub(crate) fn socket_domain(fd: RawFd) -> io::Result<libc::c_int> {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockname(
+            fd,
+            &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    match ret {
+        0 => Ok(storage.ss_family as libc::c_int),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+fn socket_option(fd: RawFd, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    match ret {
+        0 => Ok(value),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Whether `fd` is a stream socket in listening state.
+pub(crate) fn is_listening_stream(fd: RawFd) -> io::Result<bool> {
+    Ok(socket_option(fd, libc::SO_TYPE)? == libc::SOCK_STREAM
+        && socket_option(fd, libc::SO_ACCEPTCONN)? != 0)
+}
+
+/// Listener on a Unix domain socket, analogous to [TcpIncoming](crate::TcpIncoming).
+/// The socket file is removed when a listener created using [UnixIncoming::bind] is dropped.
+pub struct UnixIncoming {
+    listener: Arc<Async<std::os::unix::net::UnixListener>>,
+    readable: Pin<Box<ReadableOwned<std::os::unix::net::UnixListener>>>,
+    path: PathBuf,
+    /// Device and inode of the socket file to remove on drop.
+    owned_inode: Option<(u64, u64)>,
+    pub(crate) shutdown: Option<ShutdownSignal>,
+    terminated: bool,
+}
+
+impl UnixIncoming {
+    /// Bind to the socket file at `path`. A stale socket file left behind by a previous process
+    /// is removed, while a socket still accepting connections results in
+    /// [io::ErrorKind::AddrInUse].
+    pub fn bind(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        remove_stale_socket(path)?;
+        let listener = Async::<std::os::unix::net::UnixListener>::bind(path)?;
+        Self::from_bound(listener, path)
+    }
+    /// Like [UnixIncoming::bind], but setting the permission bits of the socket file (e.g.
+    /// `0o660`). The socket is bound in a private directory next to `path` and moved into place
+    /// once its permissions are set, so it never accepts connections with the default mode.
+    pub fn bind_with_permissions(path: impl AsRef<Path>, mode: u32) -> io::Result<Self> {
+        let path = path.as_ref();
+        remove_stale_socket(path)?;
+        let dir = private_dir(path)?;
+        let bound = dir.join("socket");
+        let listener =
+            Async::<std::os::unix::net::UnixListener>::bind(&bound).and_then(|listener| {
+                fs::set_permissions(&bound, fs::Permissions::from_mode(mode))?;
+                fs::rename(&bound, path)?;
+                Ok(listener)
+            });
+        let _ = fs::remove_file(&bound);
+        let _ = fs::remove_dir(&dir);
+        Self::from_bound(listener?, path)
+    }
+    fn from_bound(
+        listener: Async<std::os::unix::net::UnixListener>,
+        path: &Path,
+    ) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let mut incoming = Self::from_async(listener, path.to_path_buf());
+        incoming.owned_inode = Some((metadata.dev(), metadata.ino()));
+        Ok(incoming)
+    }
+    /// Adopt an already bound listener, e.g. one inherited from a parent process. The socket file
+    /// is left in place when the listener is dropped.
+    pub fn from_std(listener: std::os::unix::net::UnixListener) -> io::Result<Self> {
+        let path = listener.local_addr()?.as_pathname().map(Path::to_path_buf);
+        Ok(Self::from_async(
+            Async::new(listener)?,
+            path.unwrap_or_default(),
+        ))
+    }
+    /// Adopt a listening socket passed as file descriptor, see [UnixIncoming::from_std].
+    pub fn from_fd(fd: OwnedFd) -> io::Result<Self> {
+        if socket_domain(fd.as_raw_fd())? != libc::AF_UNIX {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a unix domain socket",
+            ));
+        }
+        if !is_listening_stream(fd.as_raw_fd())? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a listening stream socket",
+            ));
+        }
+        Self::from_std(std::os::unix::net::UnixListener::from(fd))
+    }
+    fn from_async(listener: Async<std::os::unix::net::UnixListener>, path: PathBuf) -> Self {
+        let listener = Arc::new(listener);
+        let readable = Box::pin(listener.clone().readable_owned());
+        Self {
+            listener,
+            readable,
+            path,
+            owned_inode: None,
+            shutdown: None,
+            terminated: false,
+        }
+    }
+    /// Stop accepting connections once shutdown is triggered, see [Shutdown](crate::Shutdown).
+    /// The signal is passed on to streams created using the chaining methods.
+    pub fn graceful_shutdown(mut self, signal: ShutdownSignal) -> Self {
+        self.shutdown = Some(signal);
+        self
+    }
+    pub fn tls_with_config<F: FnMut(&ClientHello) -> Arc<ServerConfig>>(
+        self,
+        f: F,
+    ) -> TlsIncoming<F, UnixStream, Self> {
+        let shutdown = self.shutdown.clone();
+        TlsIncoming::with_transport(self, f).with_optional_shutdown(shutdown)
+    }
+    #[allow(clippy::type_complexity)]
+    pub fn tls(
+        self,
+        cert_chain: Vec<CertificateDer<'static>>,
+        key_der: PrivateKeyDer<'static>,
+    ) -> Result<
+        TlsIncoming<impl FnMut(&ClientHello) -> Arc<ServerConfig>, UnixStream, Self>,
+        rustls_acme::futures_rustls::rustls::Error,
+    > {
+        let config = single_cert_config(cert_chain, key_der, None)?;
+        Ok(self.tls_with_config(move |_| config.clone()))
+    }
+    pub fn or_tcp(self) -> TcpOrTlsIncoming {
+        let mut tcp_or_tls = TcpOrTlsIncoming::new();
+        tcp_or_tls.push(self);
+        tcp_or_tls
+    }
+    pub fn http(self) -> HttpIncoming<UnixStream, Self> {
+        let shutdown = self.shutdown.clone();
+        HttpIncoming::new(self).with_optional_shutdown(shutdown)
+    }
+    /// Path of the socket file, empty for unnamed and abstract sockets.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl<F: FnMut(&ClientHello) -> Arc<ServerConfig> + 'static>
+    TlsIncoming<F, UnixStream, UnixIncoming>
+{
+    pub fn or_tcp(self) -> TcpOrTlsIncoming {
+        let mut tcp_or_tls = TcpOrTlsIncoming::new();
+        tcp_or_tls.push(self);
+        tcp_or_tls
+    }
+}
+
+/// Create a directory only accessible by the current user next to `path`, on the same file system
+/// so the socket can be renamed into place.
+fn private_dir(path: &Path) -> io::Result<PathBuf> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid socket path"))?;
+    let mut dir_name = std::ffi::OsString::from(".");
+    dir_name.push(name);
+    dir_name.push(format!(".{}.tmp", std::process::id()));
+    let dir = path.with_file_name(dir_name);
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "path exists and is not a socket",
+        ));
+    }
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "socket is in use by another listener",
+        )),
+        Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(err) => Err(err),
+    }
+}
+
+impl Drop for UnixIncoming {
+    fn drop(&mut self) {
+        // Only remove the socket file if it has not been replaced by another listener.
+        if let (Some(inode), Ok(metadata)) = (self.owned_inode, fs::metadata(&self.path)) {
+            if (metadata.dev(), metadata.ino()) == inode {
+                let _ = fs::remove_file(&self.path);
+            }
+        }
+    }
+}
+
+impl Stream for UnixIncoming {
+    type Item = UnixStream;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(shutdown) = &mut self.shutdown {
+            if shutdown.poll_state(cx) != ShutdownState::Running {
+                self.terminated = true;
+            }
+        }
+        if self.terminated {
+            return Poll::Ready(None);
+        }
+        let listener = self.listener.clone();
+        loop {
+            match Box::pin(listener.accept()).poll_unpin(cx) {
+                Poll::Ready(result) => match result {
+                    Ok((stream, _)) => return Poll::Ready(Some(stream.into())),
+                    Err(err) => log::debug!("unix accept error: {:?}", err),
+                },
+                Poll::Pending => match self.readable.as_mut().poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(_) => self.readable = Box::pin(listener.clone().readable_owned()),
+                },
+            }
+        }
+    }
+}
+
+impl AsRawFd for UnixIncoming {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl FusedStream for UnixIncoming {
+    fn is_terminated(&self) -> bool {
+        self.terminated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    /// Unique socket path in the temporary directory, removed beforehand.
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.sock", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn stale_socket_removed() {
+        let path = socket_path("stale");
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let incoming = UnixIncoming::bind(&path).unwrap();
+        std::os::unix::net::UnixStream::connect(&path).unwrap();
+        drop(incoming);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn live_socket_kept() {
+        let path = socket_path("live");
+        let _incoming = UnixIncoming::bind(&path).unwrap();
+        let err = UnixIncoming::bind(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn non_socket_kept() {
+        let path = socket_path("file");
+        fs::write(&path, b"").unwrap();
+        let err = UnixIncoming::bind(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn permissions() {
+        let path = socket_path("mode");
+        let incoming = UnixIncoming::bind_with_permissions(&path, 0o600).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        std::os::unix::net::UnixStream::connect(&path).unwrap();
+        let parent = fs::read_dir(path.parent().unwrap()).unwrap();
+        let tmp = format!(".{}.", path.file_name().unwrap().to_str().unwrap());
+        assert!(!parent
+            .filter_map(Result::ok)
+            .any(|entry| entry.file_name().to_string_lossy().starts_with(&tmp)));
+        drop(incoming);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn peer_credentials() {
+        let path = socket_path("cred");
+        let mut incoming = UnixIncoming::bind(&path).unwrap();
+        let _client = std::os::unix::net::UnixStream::connect(&path).unwrap();
+        let stream = block_on(incoming.next()).unwrap();
+        let cred = stream.peer_cred().unwrap();
+        assert_eq!(cred.uid, unsafe { libc::getuid() });
+        assert_eq!(cred.gid, unsafe { libc::getgid() });
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        assert_eq!(cred.pid, Some(std::process::id() as i32));
+    }
+}
@@ -1,12 +1,21 @@
-use crate::{HttpRequest, IsTls, TcpOrTlsIncoming, TcpOrTlsStream};
+use crate::ws_transport::CloseHandle;
+use crate::{
+    ClientCertificate, HasTlsInfo, HttpRequest, IsTls, PeerAddr, ProxyHeader, ProxyInfo,
+    TcpOrTlsIncoming, TcpOrTlsStream, TlsInfo, WsConfig, WsLatency, WsTransport,
+};
+#[cfg(unix)]
+use crate::{PeerCred, PeerCredentials};
 use async_http_codec::internal::buffer_write::BufferWrite;
-use async_http_codec::{RequestHead, ResponseHead};
-use async_ws::connection::WsConfig;
+use async_http_codec::ResponseHead;
+use async_ws::connection::WsConfig as ConnectionConfig;
 use async_ws::http::{is_upgrade_request, upgrade_response};
 use futures::prelude::*;
 use futures::stream::FusedStream;
-use http::{HeaderMap, Method, Request, Uri, Version};
+use http::header::{IntoHeaderName, SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_PROTOCOL};
+use http::{HeaderMap, HeaderValue, Method, Request, StatusCode, Uri, Version};
+use rustls_acme::futures_rustls::pki_types::CertificateDer;
 use std::io;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
@@ -17,6 +26,7 @@ pub type WsConnectionError = async_ws::connection::WsConnectionError;
 pub type WsMessageReader<IO = TcpOrTlsStream> = async_ws::connection::WsMessageReader<IO>;
 pub type WsMessageWriter<IO = TcpOrTlsStream> = async_ws::connection::WsMessageWriter<IO>;
 
+#[allow(clippy::large_enum_variant)]
 pub enum HttpOrWs<IO: AsyncRead + AsyncWrite + Unpin = TcpOrTlsStream> {
     Http(HttpRequest<IO>),
     Ws(WsUpgradeRequest<IO>),
@@ -31,6 +41,58 @@ impl<IO: AsyncRead + AsyncWrite + Unpin + IsTls> IsTls for HttpOrWs<IO> {
     }
 }
 
+impl<IO: AsyncRead + AsyncWrite + Unpin + PeerAddr> PeerAddr for HttpOrWs<IO> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            HttpOrWs::Http(http) => http.peer_addr(),
+            HttpOrWs::Ws(ws) => ws.peer_addr(),
+        }
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            HttpOrWs::Http(http) => http.local_addr(),
+            HttpOrWs::Ws(ws) => ws.local_addr(),
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin + HasTlsInfo> HasTlsInfo for HttpOrWs<IO> {
+    fn tls_info(&self) -> Option<TlsInfo> {
+        match self {
+            HttpOrWs::Http(http) => http.tls_info(),
+            HttpOrWs::Ws(ws) => ws.tls_info(),
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin + ClientCertificate> ClientCertificate for HttpOrWs<IO> {
+    fn peer_certificates(&self) -> Option<&[CertificateDer<'_>]> {
+        match self {
+            HttpOrWs::Http(http) => http.peer_certificates(),
+            HttpOrWs::Ws(ws) => ws.peer_certificates(),
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin + ProxyInfo> ProxyInfo for HttpOrWs<IO> {
+    fn proxy_header(&self) -> Option<&ProxyHeader> {
+        match self {
+            HttpOrWs::Http(http) => http.proxy_header(),
+            HttpOrWs::Ws(ws) => ws.proxy_header(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl<IO: AsyncRead + AsyncWrite + Unpin + PeerCred> PeerCred for HttpOrWs<IO> {
+    fn peer_cred(&self) -> io::Result<PeerCredentials> {
+        match self {
+            HttpOrWs::Http(http) => http.peer_cred(),
+            HttpOrWs::Ws(ws) => ws.peer_cred(),
+        }
+    }
+}
+
 pub struct HttpOrWsIncoming<
     IO: AsyncRead + AsyncWrite + Unpin = TcpOrTlsStream,
     T: Stream<Item = HttpRequest<IO>> + Unpin = TcpOrTlsIncoming,
@@ -68,20 +130,14 @@ impl<IO: AsyncRead + AsyncWrite + Unpin, T: Stream<Item = HttpRequest<IO>> + Unp
             Poll::Ready(Some(request)) => request,
         };
 
-        let request = request.into_inner();
-        if !is_upgrade_request(&request) {
-            return Poll::Ready(Some(HttpOrWs::Http(HttpRequest::from_inner(request))));
+        let head = Request::from(request.head.clone());
+        if !is_upgrade_request(&head) {
+            return Poll::Ready(Some(HttpOrWs::Http(request)));
         }
 
-        let response = upgrade_response(&request).unwrap();
-        let (request_head, request_body) = request.into_parts();
-        let request_head = RequestHead::from(request_head);
-        let (_, transport) = request_body.into_inner();
-        let response_head = ResponseHead::from(response);
         Poll::Ready(Some(HttpOrWs::Ws(WsUpgradeRequest {
-            request_head,
-            response_head,
-            transport,
+            request,
+            response_headers: HeaderMap::new(),
         })))
     }
 }
@@ -94,15 +150,65 @@ impl<IO: AsyncRead + AsyncWrite + Unpin, T: Stream<Item = HttpRequest<IO>> + Unp
     }
 }
 
-pub struct WsUpgradeRequest<IO: AsyncRead + AsyncWrite + Unpin> {
-    pub(crate) request_head: RequestHead<'static>,
-    pub(crate) response_head: ResponseHead<'static>,
-    pub(crate) transport: IO,
+/// A websocket upgrade request, which may be accepted using [Self::upgrade] or answered like any
+/// other request after [Self::into_http], e.g. to deny unauthenticated clients.
+///
+/// ```no_run
+/// # use async_web_server::WsUpgradeRequest;
+/// # use http::{HeaderValue, StatusCode};
+/// # async fn handle(mut request: WsUpgradeRequest) -> std::io::Result<()> {
+/// if !request.request_headers().contains_key("authorization") {
+///     return request.reject(StatusCode::UNAUTHORIZED, "missing token").await;
+/// }
+/// request.insert_response_header("x-session", HeaderValue::from_static("42"));
+/// let ws = request.upgrade().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct WsUpgradeRequest<IO: AsyncRead + AsyncWrite + Unpin = TcpOrTlsStream> {
+    pub(crate) request: HttpRequest<IO>,
+    pub(crate) response_headers: HeaderMap,
 }
 
 impl<IO: AsyncRead + AsyncWrite + Unpin + IsTls> IsTls for WsUpgradeRequest<IO> {
     fn is_tls(&self) -> bool {
-        self.transport.is_tls()
+        self.request.transport.is_tls()
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin + PeerAddr> PeerAddr for WsUpgradeRequest<IO> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.request.transport.peer_addr()
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.request.transport.local_addr()
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin + HasTlsInfo> HasTlsInfo for WsUpgradeRequest<IO> {
+    fn tls_info(&self) -> Option<TlsInfo> {
+        self.request.transport.tls_info()
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin + ClientCertificate> ClientCertificate
+    for WsUpgradeRequest<IO>
+{
+    fn peer_certificates(&self) -> Option<&[CertificateDer<'_>]> {
+        self.request.transport.peer_certificates()
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin + ProxyInfo> ProxyInfo for WsUpgradeRequest<IO> {
+    fn proxy_header(&self) -> Option<&ProxyHeader> {
+        self.request.transport.proxy_header()
+    }
+}
+
+#[cfg(unix)]
+impl<IO: AsyncRead + AsyncWrite + Unpin + PeerCred> PeerCred for WsUpgradeRequest<IO> {
+    fn peer_cred(&self) -> io::Result<PeerCredentials> {
+        self.request.transport.peer_cred()
     }
 }
 
@@ -116,34 +222,185 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> WsUpgradeRequest<IO> {
     /// let transport = request.into_inner();
     /// ```
     pub fn into_inner(self) -> Request<IO> {
-        Request::from_parts(self.request_head.into(), self.transport)
+        Request::from_parts(self.request.head.into(), self.request.transport)
+    }
+    /// Handle the request as a plain HTTP request instead of upgrading it, e.g. to respond with
+    /// `401 Unauthorized`.
+    pub fn into_http(self) -> HttpRequest<IO> {
+        self.request
+    }
+    /// Refuse the upgrade, sending a response with the given status and body.
+    pub async fn reject(self, status: StatusCode, body: impl AsRef<[u8]>) -> io::Result<()> {
+        let mut response = self.request.response().await?;
+        response.set_status(status);
+        response.send(body).await
     }
     /// Access the original requests headers as [http::HeaderMap].
     pub fn request_headers(&self) -> &HeaderMap {
-        self.request_head.headers()
+        self.request.headers()
     }
     /// Access the original requests URI as [http::Uri].
     pub fn uri(&self) -> &Uri {
-        &self.request_head.uri()
+        self.request.uri()
     }
     /// Return the original requests method as [http::Method].
     pub fn method(&self) -> Method {
-        self.request_head.method().clone()
+        self.request.method()
     }
     /// Return the HTTP version as [http::Version].
     pub fn version(&self) -> Version {
-        self.request_head.version()
+        self.request.version()
+    }
+    /// Access the headers added to the `101 Switching Protocols` response as [http::HeaderMap].
+    pub fn response_headers(&self) -> &HeaderMap {
+        &self.response_headers
+    }
+    /// Access the headers added to the `101 Switching Protocols` response as mutable
+    /// [http::HeaderMap]. Headers required by the handshake take precedence.
+    pub fn response_headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.response_headers
+    }
+    /// Insert a header into the `101 Switching Protocols` response (chainable).
+    pub fn insert_response_header(
+        &mut self,
+        key: impl IntoHeaderName,
+        value: HeaderValue,
+    ) -> &mut Self {
+        self.response_headers.insert(key, value);
+        self
+    }
+    /// Subprotocols offered by the client in the `Sec-WebSocket-Protocol` header, in the client's
+    /// order of preference.
+    pub fn protocols(&self) -> Vec<&str> {
+        self.request_headers()
+            .get_all(SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .collect()
     }
-    /// Upgrade to a websocket connection.
+    /// Accept the first of the supported subprotocols, in the server's order of preference, that
+    /// was offered by the client and return it. If none was offered, no subprotocol is selected
+    /// and the upgrade may be refused using [Self::reject].
+    ///
+    /// ```no_run
+    /// # use async_web_server::WsUpgradeRequest;
+    /// # use http::StatusCode;
+    /// # async fn handle(mut request: WsUpgradeRequest) -> std::io::Result<()> {
+    /// if request.select_protocol(["graphql-transport-ws", "graphql-ws"]).is_none() {
+    ///     return request.reject(StatusCode::BAD_REQUEST, "unsupported subprotocol").await;
+    /// }
+    /// let (ws, protocol) = request.upgrade().with_protocol().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn select_protocol(
+        &mut self,
+        supported: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Option<&str> {
+        let offered = self.protocols();
+        let selected = supported
+            .into_iter()
+            .find(|p| offered.contains(&p.as_ref()))
+            .and_then(|p| HeaderValue::from_str(p.as_ref()).ok());
+        match selected {
+            Some(value) => self.response_headers.insert(SEC_WEBSOCKET_PROTOCOL, value),
+            None => self.response_headers.remove(SEC_WEBSOCKET_PROTOCOL),
+        };
+        self.protocol()
+    }
+    /// The selected subprotocol, see [Self::select_protocol].
+    pub fn protocol(&self) -> Option<&str> {
+        self.response_headers
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|v| v.to_str().ok())
+    }
+    /// Upgrade to a websocket connection without limits or extensions.
     pub fn upgrade(self) -> WsAccept<IO> {
+        self.accept(ConnectionConfig::server(), None, |transport| transport)
+    }
+    /// Upgrade to a websocket connection with the given [WsConfig], e.g. to bound the memory used
+    /// by each connection, to compress messages with `permessage-deflate` or to detect
+    /// unresponsive peers.
+    pub fn upgrade_with_config(self, config: WsConfig) -> WsAccept<WsTransport<IO>> {
+        let deflate = config
+            .deflate
+            .and_then(|deflate| deflate.negotiate(self.request_headers()));
+        let extensions = deflate.as_ref().map(|(_, response)| response.clone());
+        let mut handles = None;
+        let mut accept = self.accept(config.connection_config(), extensions, |transport| {
+            let transport = WsTransport::new(transport, &config, deflate.map(|(params, _)| params));
+            handles = Some((transport.latency(), transport.close_handle()));
+            transport
+        });
+        accept.handles = handles;
+        accept
+    }
+    fn accept<T: AsyncRead + AsyncWrite + Unpin>(
+        self,
+        config: ConnectionConfig,
+        extensions: Option<HeaderValue>,
+        wrap: impl FnOnce(IO) -> T,
+    ) -> WsAccept<T> {
+        let protocol = self.protocol().map(str::to_string);
+        let Self {
+            request,
+            mut response_headers,
+        } = self;
+        let response = upgrade_response(&Request::from(request.head.clone()))
+            .expect("upgrade request has been validated");
+        let mut response_head = ResponseHead::from(response);
+        match extensions {
+            Some(extensions) => response_headers.insert(SEC_WEBSOCKET_EXTENSIONS, extensions),
+            None => response_headers.remove(SEC_WEBSOCKET_EXTENSIONS),
+        };
+        response_headers.extend(response_head.headers().clone());
+        *response_head.headers_mut() = response_headers;
         WsAccept {
-            response: self.response_head.encode(self.transport),
+            response: response_head.encode(wrap(request.transport)),
+            protocol,
+            config: Some(config),
+            handles: None,
         }
     }
 }
 
 pub struct WsAccept<IO: AsyncRead + AsyncWrite + Unpin> {
     response: BufferWrite<IO>,
+    protocol: Option<String>,
+    config: Option<ConnectionConfig>,
+    handles: Option<(WsLatency, CloseHandle)>,
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> WsAccept<IO> {
+    /// The subprotocol of the connection, see [WsUpgradeRequest::select_protocol].
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+    /// Complete the upgrade, returning the connection together with its subprotocol.
+    pub async fn with_protocol(mut self) -> io::Result<(WsConnection<IO>, Option<String>)> {
+        let protocol = self.protocol.take();
+        let connection = (&mut self).await?;
+        Ok((connection, protocol))
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> WsAccept<WsTransport<IO>> {
+    /// Round-trip time of the heartbeat pings of the connection, see [WsTransport::latency].
+    pub fn latency(&self) -> WsLatency {
+        self.handles
+            .as_ref()
+            .map(|(latency, _)| latency.clone())
+            .unwrap_or_default()
+    }
+    pub(crate) fn close_handle(&self) -> CloseHandle {
+        self.handles
+            .as_ref()
+            .map(|(_, close)| close.clone())
+            .unwrap_or_default()
+    }
 }
 
 impl<IO: AsyncRead + AsyncWrite + Unpin> Future for WsAccept<IO> {
@@ -152,7 +409,8 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> Future for WsAccept<IO> {
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         match self.response.poll_unpin(cx) {
             Poll::Ready(Ok(transport)) => {
-                Poll::Ready(Ok(WsConnection::with_config(transport, WsConfig::server())))
+                let config = self.config.take().unwrap_or_else(ConnectionConfig::server);
+                Poll::Ready(Ok(WsConnection::with_config(transport, config)))
             }
             Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
             Poll::Pending => Poll::Pending,
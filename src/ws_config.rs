@@ -0,0 +1,381 @@
+use crate::WsHeartbeat;
+use http::header::SEC_WEBSOCKET_EXTENSIONS;
+use http::{HeaderMap, HeaderValue};
+use std::time::Duration;
+
+/// Settings for [WsUpgradeRequest::upgrade_with_config](crate::WsUpgradeRequest::upgrade_with_config).
+///
+/// ```no_run
+/// # use async_web_server::{WsConfig, WsDeflate, WsUpgradeRequest};
+/// # async fn handle(request: WsUpgradeRequest) -> std::io::Result<()> {
+/// let config = WsConfig::server()
+///     .max_frame_size(1 << 16)
+///     .max_message_size(1 << 20)
+///     .deflate(WsDeflate::new().max_window_bits(12));
+/// let ws = request.upgrade_with_config(config).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Copy, Clone, Debug)]
+pub struct WsConfig {
+    pub(crate) require_mask: bool,
+    pub(crate) timeout: Duration,
+    pub(crate) max_frame_size: Option<u64>,
+    pub(crate) max_message_size: Option<u64>,
+    pub(crate) deflate: Option<WsDeflate>,
+    pub(crate) heartbeat: Option<WsHeartbeat>,
+}
+
+impl WsConfig {
+    pub fn server() -> Self {
+        WsConfig {
+            require_mask: true,
+            timeout: Duration::from_secs(10),
+            max_frame_size: None,
+            max_message_size: None,
+            deflate: None,
+            heartbeat: None,
+        }
+    }
+    /// Close the connection with status `1002` when receiving an unmasked frame, as required for
+    /// servers by RFC 6455 (enabled by default).
+    pub fn require_mask(mut self, require: bool) -> Self {
+        self.require_mask = require;
+        self
+    }
+    /// Time without receiving anything before the connection sends a ping, and again before
+    /// failing (10 seconds by default). Not used with a [Self::heartbeat].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    /// Close the connection with status `1009` when receiving a frame with a larger payload
+    /// (unlimited by default).
+    pub fn max_frame_size(mut self, size: u64) -> Self {
+        self.max_frame_size = Some(size);
+        self
+    }
+    /// Close the connection with status `1009` when receiving a larger message, measured after
+    /// decompression (unlimited by default).
+    pub fn max_message_size(mut self, size: u64) -> Self {
+        self.max_message_size = Some(size);
+        self
+    }
+    /// Accept the `permessage-deflate` extension if offered by the client.
+    pub fn deflate(mut self, deflate: WsDeflate) -> Self {
+        self.deflate = Some(deflate);
+        self
+    }
+    /// Send keepalive pings and fail the connection if the peer stops responding, see
+    /// [WsHeartbeat].
+    pub fn heartbeat(mut self, heartbeat: WsHeartbeat) -> Self {
+        self.heartbeat = Some(heartbeat);
+        self
+    }
+    pub(crate) fn connection_config(&self) -> async_ws::connection::WsConfig {
+        let mut config = async_ws::connection::WsConfig::server();
+        config.timeout = match self.heartbeat {
+            // The heartbeat replaces the ping of the connection, which uses one period both for
+            // pinging and for failing, fails without a close frame and cannot tell its pongs
+            // apart. A timeout this long never fires.
+            Some(_) => Duration::MAX,
+            None => self.timeout,
+        };
+        config
+    }
+}
+
+impl Default for WsConfig {
+    fn default() -> Self {
+        Self::server()
+    }
+}
+
+/// Settings for the `permessage-deflate` extension (RFC 7692), see [WsConfig::deflate].
+///
+/// Offers the server cannot satisfy, e.g. asking for a server window of 256 bytes, are declined,
+/// so the client may fall back to another offer or to uncompressed messages.
+#[derive(Copy, Clone, Debug)]
+pub struct WsDeflate {
+    level: u32,
+    max_window_bits: u8,
+    client_max_window_bits: u8,
+    no_context_takeover: bool,
+    client_no_context_takeover: bool,
+}
+
+impl Default for WsDeflate {
+    fn default() -> Self {
+        WsDeflate {
+            level: 6,
+            max_window_bits: 15,
+            client_max_window_bits: 15,
+            no_context_takeover: false,
+            client_no_context_takeover: false,
+        }
+    }
+}
+
+impl WsDeflate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Compression level from 0 to 9 (6 by default).
+    pub fn level(mut self, level: u32) -> Self {
+        self.level = level.min(9);
+        self
+    }
+    /// Window for compressing messages as a power of two from 9 to 15 (15 by default). Smaller
+    /// windows use less memory per connection.
+    pub fn max_window_bits(mut self, bits: u8) -> Self {
+        self.max_window_bits = bits.clamp(9, 15);
+        self
+    }
+    /// Window the client may compress messages with as a power of two from 8 to 15 (15 by
+    /// default). Only applies to clients offering `client_max_window_bits`.
+    pub fn client_max_window_bits(mut self, bits: u8) -> Self {
+        self.client_max_window_bits = bits.clamp(8, 15);
+        self
+    }
+    /// Compress each message on its own, so no compression state is kept between messages.
+    pub fn no_context_takeover(mut self, enable: bool) -> Self {
+        self.no_context_takeover = enable;
+        self
+    }
+    /// Require the client to compress each message on its own.
+    pub fn client_no_context_takeover(mut self, enable: bool) -> Self {
+        self.client_no_context_takeover = enable;
+        self
+    }
+    /// Accept the first `permessage-deflate` offer in the `Sec-WebSocket-Extensions` request
+    /// headers which can be satisfied, returning the negotiated parameters and response header.
+    pub(crate) fn negotiate(&self, headers: &HeaderMap) -> Option<(DeflateParams, HeaderValue)> {
+        headers
+            .get_all(SEC_WEBSOCKET_EXTENSIONS)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(parse_offer)
+            .find_map(|offer| self.accept(offer))
+    }
+    fn accept(&self, offer: DeflateOffer) -> Option<(DeflateParams, HeaderValue)> {
+        let server_window_bits = match offer.server_max_window_bits {
+            Some(bits) => bits.min(self.max_window_bits),
+            None => self.max_window_bits,
+        };
+        // Compressing with a window of 256 bytes is not supported.
+        if server_window_bits < 9 {
+            return None;
+        }
+        let client_window_bits = match offer.client_max_window_bits {
+            Some(bits) => bits.unwrap_or(15).min(self.client_max_window_bits),
+            None => 15,
+        };
+        let params = DeflateParams {
+            level: self.level,
+            server_window_bits,
+            client_window_bits,
+            server_no_context_takeover: offer.server_no_context_takeover
+                || self.no_context_takeover,
+            client_no_context_takeover: offer.client_no_context_takeover
+                || self.client_no_context_takeover,
+        };
+        let mut response = String::from("permessage-deflate");
+        if params.server_no_context_takeover {
+            response.push_str("; server_no_context_takeover");
+        }
+        if params.client_no_context_takeover {
+            response.push_str("; client_no_context_takeover");
+        }
+        if offer.server_max_window_bits.is_some() {
+            response.push_str(&format!("; server_max_window_bits={}", server_window_bits));
+        }
+        if offer.client_max_window_bits.is_some() {
+            response.push_str(&format!("; client_max_window_bits={}", client_window_bits));
+        }
+        Some((params, HeaderValue::from_str(&response).ok()?))
+    }
+}
+
+/// Negotiated `permessage-deflate` parameters.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) struct DeflateParams {
+    pub(crate) level: u32,
+    pub(crate) server_window_bits: u8,
+    pub(crate) client_window_bits: u8,
+    pub(crate) server_no_context_takeover: bool,
+    pub(crate) client_no_context_takeover: bool,
+}
+
+#[derive(Default)]
+struct DeflateOffer {
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+    server_max_window_bits: Option<u8>,
+    client_max_window_bits: Option<Option<u8>>,
+}
+
+/// Parse a `permessage-deflate` offer, `None` for other extensions and invalid offers.
+fn parse_offer(extension: &str) -> Option<DeflateOffer> {
+    let mut params = extension.split(';').map(str::trim);
+    if !params.next()?.eq_ignore_ascii_case("permessage-deflate") {
+        return None;
+    }
+    let mut offer = DeflateOffer::default();
+    let mut seen = Vec::new();
+    for param in params {
+        let (name, value) = match param.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (param, None),
+        };
+        let name = name.to_ascii_lowercase();
+        if seen.contains(&name) {
+            return None;
+        }
+        match (name.as_str(), value) {
+            ("server_no_context_takeover", None) => offer.server_no_context_takeover = true,
+            ("client_no_context_takeover", None) => offer.client_no_context_takeover = true,
+            ("server_max_window_bits", Some(bits)) => {
+                offer.server_max_window_bits = Some(parse_window_bits(bits)?)
+            }
+            ("client_max_window_bits", None) => offer.client_max_window_bits = Some(None),
+            ("client_max_window_bits", Some(bits)) => {
+                offer.client_max_window_bits = Some(Some(parse_window_bits(bits)?))
+            }
+            _ => return None,
+        }
+        seen.push(name);
+    }
+    Some(offer)
+}
+
+fn parse_window_bits(bits: &str) -> Option<u8> {
+    if !bits.bytes().all(|b| b.is_ascii_digit()) || bits.starts_with('0') {
+        return None;
+    }
+    bits.parse().ok().filter(|bits| (8..=15).contains(bits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn negotiate(deflate: WsDeflate, offer: &str) -> Option<(DeflateParams, String)> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            SEC_WEBSOCKET_EXTENSIONS,
+            HeaderValue::from_str(offer).unwrap(),
+        );
+        deflate
+            .negotiate(&headers)
+            .map(|(params, response)| (params, response.to_str().unwrap().to_string()))
+    }
+
+    #[test]
+    fn browser_offer() {
+        let (params, response) = negotiate(
+            WsDeflate::new(),
+            "permessage-deflate; client_max_window_bits",
+        )
+        .unwrap();
+        assert_eq!(response, "permessage-deflate; client_max_window_bits=15");
+        assert_eq!(
+            params,
+            DeflateParams {
+                level: 6,
+                server_window_bits: 15,
+                client_window_bits: 15,
+                server_no_context_takeover: false,
+                client_no_context_takeover: false,
+            }
+        );
+    }
+
+    #[test]
+    fn window_bits() {
+        let (params, response) = negotiate(
+            WsDeflate::new()
+                .max_window_bits(12)
+                .client_max_window_bits(10),
+            "permessage-deflate; server_max_window_bits=14; client_max_window_bits=\"11\"",
+        )
+        .unwrap();
+        assert_eq!(
+            response,
+            "permessage-deflate; server_max_window_bits=12; client_max_window_bits=10"
+        );
+        assert_eq!(params.server_window_bits, 12);
+        assert_eq!(params.client_window_bits, 10);
+        // The client window can only be limited if the client offers it.
+        let (params, response) = negotiate(
+            WsDeflate::new().client_max_window_bits(10),
+            "permessage-deflate",
+        )
+        .unwrap();
+        assert_eq!(response, "permessage-deflate");
+        assert_eq!(params.client_window_bits, 15);
+        // A smaller server window needs no agreement.
+        let (params, response) =
+            negotiate(WsDeflate::new().max_window_bits(9), "permessage-deflate").unwrap();
+        assert_eq!(response, "permessage-deflate");
+        assert_eq!(params.server_window_bits, 9);
+    }
+
+    #[test]
+    fn context_takeover() {
+        let (params, response) = negotiate(
+            WsDeflate::new().client_no_context_takeover(true),
+            "permessage-deflate; server_no_context_takeover",
+        )
+        .unwrap();
+        assert_eq!(
+            response,
+            "permessage-deflate; server_no_context_takeover; client_no_context_takeover"
+        );
+        assert!(params.server_no_context_takeover);
+        assert!(params.client_no_context_takeover);
+        let (params, response) = negotiate(
+            WsDeflate::new().no_context_takeover(true),
+            "permessage-deflate; client_no_context_takeover",
+        )
+        .unwrap();
+        assert_eq!(
+            response,
+            "permessage-deflate; server_no_context_takeover; client_no_context_takeover"
+        );
+        assert!(params.server_no_context_takeover);
+        assert!(params.client_no_context_takeover);
+    }
+
+    #[test]
+    fn first_acceptable_offer() {
+        let (params, response) = negotiate(
+            WsDeflate::new(),
+            "x-webkit-deflate-frame, permessage-deflate; server_max_window_bits=8, \
+             permessage-deflate; server_max_window_bits=10, permessage-deflate",
+        )
+        .unwrap();
+        assert_eq!(response, "permessage-deflate; server_max_window_bits=10");
+        assert_eq!(params.server_window_bits, 10);
+    }
+
+    #[test]
+    fn invalid_offers() {
+        for offer in [
+            "permessage-deflate; server_max_window_bits",
+            "permessage-deflate; server_max_window_bits=16",
+            "permessage-deflate; server_max_window_bits=010",
+            "permessage-deflate; client_max_window_bits=7",
+            "permessage-deflate; client_max_window_bits=abc",
+            "permessage-deflate; server_no_context_takeover=1",
+            "permessage-deflate; server_no_context_takeover; server_no_context_takeover",
+            "permessage-deflate; unknown_parameter",
+            "permessage-deflate; server_max_window_bits=8",
+            "x-webkit-deflate-frame",
+            "",
+        ] {
+            assert!(negotiate(WsDeflate::new(), offer).is_none(), "{}", offer);
+        }
+        assert!(WsDeflate::new().negotiate(&HeaderMap::new()).is_none());
+    }
+}
@@ -0,0 +1,140 @@
+use async_io::Timer;
+use futures::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+const OPCODE_PING: u8 = 0x89;
+
+/// Keepalive settings for [WsConfig::heartbeat](crate::WsConfig::heartbeat).
+///
+/// A ping is sent every `interval`. If nothing is received from the peer for `timeout` after an
+/// unanswered ping, a close frame with status `1011` is sent and the connection fails with
+/// [io::ErrorKind::TimedOut](std::io::ErrorKind::TimedOut). Pings are only sent while the
+/// connection is being polled, e.g. by waiting for the next message.
+///
+/// ```no_run
+/// # use async_web_server::{WsConfig, WsHeartbeat, WsUpgradeRequest};
+/// # use std::time::Duration;
+/// # async fn handle(request: WsUpgradeRequest) -> std::io::Result<()> {
+/// let heartbeat = WsHeartbeat::new(Duration::from_secs(15), Duration::from_secs(10));
+/// let accept = request.upgrade_with_config(WsConfig::server().heartbeat(heartbeat));
+/// let latency = accept.latency();
+/// let ws = accept.await?;
+/// log::info!("round-trip time: {:?}", latency.get());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Copy, Clone, Debug)]
+pub struct WsHeartbeat {
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl WsHeartbeat {
+    pub fn new(interval: Duration, timeout: Duration) -> Self {
+        WsHeartbeat { interval, timeout }
+    }
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+/// Round-trip time of the most recently answered ping of a connection, see
+/// [WsTransport::latency](crate::WsTransport::latency).
+#[derive(Clone, Debug, Default)]
+pub struct WsLatency(Arc<Mutex<Option<Duration>>>);
+
+impl WsLatency {
+    pub fn get(&self) -> Option<Duration> {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Heartbeat state of a [WsTransport](crate::WsTransport). Each ping carries a sequence number,
+/// so only the pong answering the latest ping is taken as a latency sample.
+pub(crate) struct Heartbeat {
+    config: WsHeartbeat,
+    latency: WsLatency,
+    sequence: u64,
+    due: bool,
+    outstanding: Option<([u8; 8], Instant)>,
+    next_ping: Instant,
+    unanswered_since: Option<Instant>,
+    timer: Timer,
+}
+
+impl Heartbeat {
+    pub(crate) fn new(config: WsHeartbeat) -> Self {
+        let next_ping = Instant::now() + config.interval;
+        Heartbeat {
+            config,
+            latency: WsLatency::default(),
+            sequence: 0,
+            due: false,
+            outstanding: None,
+            next_ping,
+            unanswered_since: None,
+            timer: Timer::at(next_ping),
+        }
+    }
+    pub(crate) fn latency(&self) -> WsLatency {
+        self.latency.clone()
+    }
+    /// Schedule pings, resolving once the peer has not responded within the timeout.
+    pub(crate) fn poll_timeout(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            let now = Instant::now();
+            let timeout_at = self
+                .unanswered_since
+                .map(|since| since + self.config.timeout);
+            if matches!(timeout_at, Some(timeout_at) if now >= timeout_at) {
+                log::debug!(
+                    "websocket peer did not respond within {:?}",
+                    self.config.timeout
+                );
+                return Poll::Ready(());
+            }
+            if now >= self.next_ping {
+                self.due = true;
+                self.unanswered_since.get_or_insert(now);
+                self.next_ping = now + self.config.interval;
+                continue;
+            }
+            let deadline = match timeout_at {
+                Some(timeout_at) => timeout_at.min(self.next_ping),
+                None => self.next_ping,
+            };
+            self.timer.set_at(deadline);
+            if self.timer.poll_unpin(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+    /// Append a ping frame if one is due, which must happen at a frame boundary.
+    pub(crate) fn write_ping(&mut self, out: &mut Vec<u8>) {
+        if !self.due {
+            return;
+        }
+        self.due = false;
+        self.sequence += 1;
+        let payload = self.sequence.to_be_bytes();
+        out.extend_from_slice(&[OPCODE_PING, payload.len() as u8]);
+        out.extend_from_slice(&payload);
+        self.outstanding = Some((payload, Instant::now()));
+    }
+    pub(crate) fn received(&mut self) {
+        self.unanswered_since = None;
+    }
+    pub(crate) fn received_pong(&mut self, payload: &[u8]) {
+        if let Some((ping, sent)) = self.outstanding {
+            if payload == ping {
+                *self.latency.0.lock().unwrap() = Some(sent.elapsed());
+                self.outstanding = None;
+            }
+        }
+    }
+}
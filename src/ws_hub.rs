@@ -0,0 +1,585 @@
+use crate::ws_transport::CloseHandle;
+use crate::{
+    TcpOrTlsStream, WsAccept, WsConnection, WsMessageKind, WsMessageReader, WsMessageWriter,
+    WsSend, WsTransport,
+};
+use futures::prelude::*;
+use futures::stream::FusedStream;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// How a [WsHub] treats subscribers whose queue is full.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WsSlowConsumer {
+    /// Skip the message for this subscriber.
+    DropMessage,
+    /// Close the subscriber's connection with status `1008`.
+    Disconnect,
+}
+
+/// Fan-out of messages to websocket connections grouped in named rooms.
+///
+/// Connections upgraded with a [WsConfig](crate::WsConfig) are added using [Self::register],
+/// which returns a [WsSubscriber] yielding the messages received on the connection. Broadcast
+/// messages are queued per subscriber and written while the subscriber is polled, so each
+/// subscriber should be polled continuously, e.g. in its own task. Subscribers are removed from all rooms once their connection closes or they are
+/// dropped. Clones share the same rooms.
+///
+/// ```no_run
+/// # use async_web_server::{WsConfig, WsHub, WsUpgradeRequest};
+/// # use futures::prelude::*;
+/// # async fn handle(hub: WsHub, request: WsUpgradeRequest) -> std::io::Result<()> {
+/// let accept = request.upgrade_with_config(WsConfig::server().max_message_size(1 << 16));
+/// let mut subscriber = hub.register(accept).await?;
+/// subscriber.join("lobby");
+/// while let Some(mut message) = subscriber.next().await {
+///     let mut text = String::new();
+///     message.read_to_string(&mut text).await?;
+///     hub.broadcast_text("lobby", text);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct WsHub {
+    queue_size: usize,
+    slow_consumer: WsSlowConsumer,
+    inner: Arc<Mutex<HubInner>>,
+}
+
+#[derive(Default)]
+struct HubInner {
+    rooms: HashMap<String, HashSet<usize>>,
+    subscribers: HashMap<usize, Subscriber>,
+    next_id: usize,
+}
+
+struct Subscriber {
+    queue_size: usize,
+    slow_consumer: WsSlowConsumer,
+    rooms: HashSet<String>,
+    queue: VecDeque<HubMessage>,
+    waker: Option<Waker>,
+    disconnected: bool,
+}
+
+#[derive(Clone)]
+struct HubMessage {
+    kind: WsMessageKind,
+    data: Arc<[u8]>,
+}
+
+impl Default for WsHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WsHub {
+    /// Create a hub queueing up to 64 messages per subscriber and dropping messages beyond that.
+    pub fn new() -> Self {
+        WsHub {
+            queue_size: 64,
+            slow_consumer: WsSlowConsumer::DropMessage,
+            inner: Arc::new(Mutex::new(HubInner::default())),
+        }
+    }
+    /// Maximum number of messages queued for each subscriber registered afterwards.
+    pub fn queue_size(mut self, size: usize) -> Self {
+        self.queue_size = size.max(1);
+        self
+    }
+    /// Policy for subscribers registered afterwards whose queue is full.
+    pub fn slow_consumer(mut self, policy: WsSlowConsumer) -> Self {
+        self.slow_consumer = policy;
+        self
+    }
+    /// Complete the upgrade of a connection and add it to the hub, initially without joining any
+    /// room.
+    pub async fn register<IO: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        accept: WsAccept<WsTransport<IO>>,
+    ) -> io::Result<WsSubscriber<IO>> {
+        let close = accept.close_handle();
+        let connection = accept.await?;
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.subscribers.insert(
+            id,
+            Subscriber {
+                queue_size: self.queue_size,
+                slow_consumer: self.slow_consumer,
+                rooms: HashSet::new(),
+                queue: VecDeque::new(),
+                waker: None,
+                disconnected: false,
+            },
+        );
+        Ok(WsSubscriber {
+            id,
+            hub: self.clone(),
+            connection: Some(connection),
+            close,
+            outgoing: Outgoing::Idle,
+        })
+    }
+    /// Queue a message for every subscriber in the room and return the number of subscribers it
+    /// was queued for.
+    pub fn broadcast(&self, room: &str, kind: WsMessageKind, data: impl AsRef<[u8]>) -> usize {
+        let message = HubMessage {
+            kind,
+            data: data.as_ref().into(),
+        };
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let ids = match inner.rooms.get(room) {
+            Some(ids) => ids,
+            None => return 0,
+        };
+        let mut queued = 0;
+        for id in ids {
+            let subscriber = match inner.subscribers.get_mut(id) {
+                Some(subscriber) if !subscriber.disconnected => subscriber,
+                _ => continue,
+            };
+            if subscriber.queue.len() < subscriber.queue_size {
+                subscriber.queue.push_back(message.clone());
+                queued += 1;
+            } else {
+                match subscriber.slow_consumer {
+                    WsSlowConsumer::DropMessage => {
+                        log::debug!("dropping message for slow websocket subscriber {}", id);
+                        continue;
+                    }
+                    WsSlowConsumer::Disconnect => {
+                        log::debug!("disconnecting slow websocket subscriber {}", id);
+                        subscriber.disconnected = true;
+                        subscriber.queue.clear();
+                    }
+                }
+            }
+            if let Some(waker) = subscriber.waker.take() {
+                waker.wake();
+            }
+        }
+        queued
+    }
+    /// Like [Self::broadcast] with a text message.
+    pub fn broadcast_text(&self, room: &str, text: impl AsRef<str>) -> usize {
+        self.broadcast(room, WsMessageKind::Text, text.as_ref())
+    }
+    /// Like [Self::broadcast] with a binary message.
+    pub fn broadcast_binary(&self, room: &str, data: impl AsRef<[u8]>) -> usize {
+        self.broadcast(room, WsMessageKind::Binary, data)
+    }
+    /// Names of the rooms with at least one subscriber.
+    pub fn rooms(&self) -> Vec<String> {
+        self.inner.lock().unwrap().rooms.keys().cloned().collect()
+    }
+    /// Number of subscribers in a room.
+    pub fn room_size(&self, room: &str) -> usize {
+        match self.inner.lock().unwrap().rooms.get(room) {
+            Some(ids) => ids.len(),
+            None => 0,
+        }
+    }
+}
+
+impl HubInner {
+    fn leave(&mut self, id: usize, room: &str) {
+        if let Some(ids) = self.rooms.get_mut(room) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                self.rooms.remove(room);
+            }
+        }
+    }
+    fn remove(&mut self, id: usize) {
+        if let Some(subscriber) = self.subscribers.remove(&id) {
+            for room in subscriber.rooms {
+                self.leave(id, &room);
+            }
+        }
+    }
+}
+
+/// A connection registered with a [WsHub], yielding the messages received on it. Polling it also
+/// writes the queued broadcast messages to the connection.
+///
+/// The stream ends once the connection closes or the subscriber is disconnected for being too
+/// slow, see [WsSlowConsumer::Disconnect].
+pub struct WsSubscriber<IO: AsyncRead + AsyncWrite + Unpin = TcpOrTlsStream> {
+    id: usize,
+    hub: WsHub,
+    connection: Option<WsConnection<WsTransport<IO>>>,
+    close: CloseHandle,
+    outgoing: Outgoing<WsTransport<IO>>,
+}
+
+enum Outgoing<T: AsyncRead + AsyncWrite + Unpin> {
+    Idle,
+    Send(WsSend<T>, Arc<[u8]>),
+    Write(WsMessageWriter<T>, Arc<[u8]>, usize),
+    Close(WsMessageWriter<T>),
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> WsSubscriber<IO> {
+    /// Identifies the subscriber within its hub.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+    /// Receive the messages broadcast to a room.
+    pub fn join(&self, room: impl Into<String>) -> &Self {
+        let room = room.into();
+        let mut inner = self.hub.inner.lock().unwrap();
+        if let Some(subscriber) = inner.subscribers.get_mut(&self.id) {
+            subscriber.rooms.insert(room.clone());
+            inner.rooms.entry(room).or_default().insert(self.id);
+        }
+        self
+    }
+    /// Stop receiving the messages broadcast to a room.
+    pub fn leave(&self, room: &str) -> &Self {
+        let mut inner = self.hub.inner.lock().unwrap();
+        if let Some(subscriber) = inner.subscribers.get_mut(&self.id) {
+            subscriber.rooms.remove(room);
+            inner.leave(self.id, room);
+        }
+        self
+    }
+    /// Rooms the subscriber has joined.
+    pub fn rooms(&self) -> Vec<String> {
+        match self.hub.inner.lock().unwrap().subscribers.get(&self.id) {
+            Some(subscriber) => subscriber.rooms.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+    /// The underlying connection, e.g. to send messages to this subscriber only. It is `None`
+    /// once the subscriber has been disconnected.
+    pub fn connection(&self) -> Option<&WsConnection<WsTransport<IO>>> {
+        self.connection.as_ref()
+    }
+    /// Write queued messages until the queue is empty or the connection is busy. Returns `false`
+    /// if the subscriber has been disconnected.
+    fn poll_outgoing(&mut self, cx: &mut Context<'_>) -> bool {
+        let connection = match &self.connection {
+            Some(connection) => connection,
+            None => return false,
+        };
+        loop {
+            self.outgoing = match mem::replace(&mut self.outgoing, Outgoing::Idle) {
+                Outgoing::Idle => {
+                    let mut inner = self.hub.inner.lock().unwrap();
+                    let subscriber = match inner.subscribers.get_mut(&self.id) {
+                        Some(subscriber) if !subscriber.disconnected => subscriber,
+                        _ => return false,
+                    };
+                    match subscriber.queue.pop_front() {
+                        Some(message) => {
+                            Outgoing::Send(connection.send(message.kind), message.data)
+                        }
+                        None => {
+                            match &subscriber.waker {
+                                Some(waker) if waker.will_wake(cx.waker()) => {}
+                                _ => subscriber.waker = Some(cx.waker().clone()),
+                            }
+                            return true;
+                        }
+                    }
+                }
+                Outgoing::Send(mut send, data) => match send.poll_unpin(cx) {
+                    Poll::Ready(Some(writer)) => Outgoing::Write(writer, data, 0),
+                    // The connection is closed, which is noticed when receiving.
+                    Poll::Ready(None) => Outgoing::Idle,
+                    Poll::Pending => {
+                        self.outgoing = Outgoing::Send(send, data);
+                        return true;
+                    }
+                },
+                Outgoing::Write(writer, data, offset) if offset == data.len() => {
+                    Outgoing::Close(writer)
+                }
+                Outgoing::Write(mut writer, data, offset) => {
+                    match Pin::new(&mut writer).poll_write(cx, &data[offset..]) {
+                        Poll::Ready(Ok(n)) => Outgoing::Write(writer, data, offset + n),
+                        Poll::Ready(Err(err)) => {
+                            log::debug!("error writing websocket broadcast message: {:?}", err);
+                            Outgoing::Idle
+                        }
+                        Poll::Pending => {
+                            self.outgoing = Outgoing::Write(writer, data, offset);
+                            return true;
+                        }
+                    }
+                }
+                Outgoing::Close(mut writer) => match Pin::new(&mut writer).poll_close(cx) {
+                    Poll::Ready(Ok(())) => Outgoing::Idle,
+                    Poll::Ready(Err(err)) => {
+                        log::debug!("error writing websocket broadcast message: {:?}", err);
+                        Outgoing::Idle
+                    }
+                    Poll::Pending => {
+                        self.outgoing = Outgoing::Close(writer);
+                        return true;
+                    }
+                },
+            }
+        }
+    }
+    fn disconnect(&mut self) {
+        self.outgoing = Outgoing::Idle;
+        self.connection = None;
+        self.hub.inner.lock().unwrap().remove(self.id);
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> Stream for WsSubscriber<IO> {
+    type Item = WsMessageReader<WsTransport<IO>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // A disconnected subscriber's transport fails with a close frame once read.
+        let closing = !self.poll_outgoing(cx);
+        if closing {
+            self.close.close();
+        }
+        let connection = match self.connection.as_mut() {
+            Some(connection) => connection,
+            None => return Poll::Ready(None),
+        };
+        loop {
+            match connection.poll_next_unpin(cx) {
+                Poll::Ready(Some(_)) if closing => continue,
+                Poll::Ready(Some(reader)) => return Poll::Ready(Some(reader)),
+                Poll::Ready(None) => {
+                    self.disconnect();
+                    return Poll::Ready(None);
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> FusedStream for WsSubscriber<IO> {
+    fn is_terminated(&self) -> bool {
+        self.connection.is_none()
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> Drop for WsSubscriber<IO> {
+    fn drop(&mut self) {
+        if let Ok(mut inner) = self.hub.inner.lock() {
+            inner.remove(self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HttpOrWs, TcpIncoming, TcpStream, WsConfig};
+    use async_io::Timer;
+    use async_ws::connection::WsConfig as ConnectionConfig;
+    use futures::executor::block_on;
+    use futures::future::{select, Either};
+    use futures::task::{waker, ArcWake};
+    use smol::net::TcpStream as ClientStream;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    const UPGRADE: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+        Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n\r\n";
+
+    struct Server {
+        incoming: Box<dyn Stream<Item = HttpOrWs<TcpStream>> + Unpin>,
+        addr: std::net::SocketAddr,
+    }
+
+    impl Server {
+        fn new() -> Self {
+            let incoming = TcpIncoming::bind(([127, 0, 0, 1], 0)).unwrap();
+            let addr = incoming.local_addr().unwrap();
+            Server {
+                incoming: Box::new(incoming.http().or_ws()),
+                addr,
+            }
+        }
+        /// Register a new connection, returning the client side after the upgrade response.
+        async fn connect(&mut self, hub: &WsHub) -> (WsSubscriber<TcpStream>, ClientStream) {
+            let mut client = ClientStream::connect(self.addr).await.unwrap();
+            client.write_all(UPGRADE).await.unwrap();
+            let request = match self.incoming.next().await {
+                Some(HttpOrWs::Ws(request)) => request,
+                _ => panic!("expected upgrade request"),
+            };
+            let accept = request.upgrade_with_config(WsConfig::server());
+            let subscriber = hub.register(accept).await.unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut byte = [0u8];
+                client.read_exact(&mut byte).await.unwrap();
+                head.push(byte[0]);
+            }
+            assert!(head.starts_with(b"HTTP/1.1 101"));
+            (subscriber, client)
+        }
+    }
+
+    fn client(stream: ClientStream) -> WsConnection<ClientStream> {
+        WsConnection::with_config(stream, ConnectionConfig::client())
+    }
+
+    /// Poll a subscriber for a while, writing its queued messages.
+    async fn drive(subscriber: &mut WsSubscriber<TcpStream>) {
+        let _ = select(subscriber.next(), Timer::after(Duration::from_millis(300))).await;
+    }
+
+    /// Next text message of a client, unless none arrives before a short timeout.
+    async fn receive(client: &mut WsConnection<ClientStream>) -> Option<String> {
+        let timer = Timer::after(Duration::from_millis(200));
+        let mut reader = match select(client.next(), timer).await {
+            Either::Left((reader, _)) => reader?,
+            Either::Right(_) => return None,
+        };
+        assert!(matches!(reader.kind(), WsMessageKind::Text));
+        let mut text = String::new();
+        reader.read_to_string(&mut text).await.unwrap();
+        Some(text)
+    }
+
+    #[test]
+    fn broadcast_to_room() {
+        let mut server = Server::new();
+        let hub = WsHub::new();
+        block_on(async {
+            let (mut a, ca) = server.connect(&hub).await;
+            let (mut b, cb) = server.connect(&hub).await;
+            let (mut c, cc) = server.connect(&hub).await;
+            let (mut ca, mut cb, mut cc) = (client(ca), client(cb), client(cc));
+            a.join("news");
+            b.join("news").join("sports");
+            c.join("sports");
+            assert_eq!(hub.room_size("news"), 2);
+            assert_eq!(hub.broadcast_text("news", "hello"), 2);
+            assert_eq!(hub.broadcast_text("weather", "sunny"), 0);
+            let received = futures::join!(
+                drive(&mut a),
+                drive(&mut b),
+                drive(&mut c),
+                receive(&mut ca),
+                receive(&mut cb),
+                receive(&mut cc),
+            );
+            assert_eq!(received.3.as_deref(), Some("hello"));
+            assert_eq!(received.4.as_deref(), Some("hello"));
+            assert_eq!(received.5, None);
+        });
+    }
+
+    #[test]
+    fn slow_consumer_drop_message() {
+        let mut server = Server::new();
+        let hub = WsHub::new().queue_size(2);
+        block_on(async {
+            let (mut subscriber, stream) = server.connect(&hub).await;
+            let mut client = client(stream);
+            subscriber.join("room");
+            assert_eq!(hub.broadcast_text("room", "1"), 1);
+            assert_eq!(hub.broadcast_text("room", "2"), 1);
+            assert_eq!(hub.broadcast_text("room", "3"), 0);
+            let (_, received) = futures::join!(drive(&mut subscriber), async {
+                let mut received = Vec::new();
+                while let Some(text) = receive(&mut client).await {
+                    received.push(text);
+                }
+                received
+            });
+            assert_eq!(received, ["1", "2"]);
+            assert_eq!(hub.broadcast_text("room", "4"), 1);
+            let (_, received) = futures::join!(drive(&mut subscriber), receive(&mut client));
+            assert_eq!(received.as_deref(), Some("4"));
+        });
+    }
+
+    #[test]
+    fn slow_consumer_disconnect() {
+        let mut server = Server::new();
+        let hub = WsHub::new()
+            .queue_size(1)
+            .slow_consumer(WsSlowConsumer::Disconnect);
+        block_on(async {
+            let (mut subscriber, mut client) = server.connect(&hub).await;
+            subscriber.join("room");
+            assert_eq!(hub.broadcast_text("room", "1"), 1);
+            assert_eq!(hub.broadcast_text("room", "2"), 0);
+            assert_eq!(hub.broadcast_text("room", "3"), 0);
+            assert!(subscriber.next().await.is_none());
+            assert!(subscriber.is_terminated());
+            assert!(subscriber.connection().is_none());
+            assert_eq!(hub.room_size("room"), 0);
+            // The queued message is discarded and the connection closed with status 1008.
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, [0x88, 0x02, 0x03, 0xf0]);
+        });
+    }
+
+    #[test]
+    fn room_cleanup() {
+        let mut server = Server::new();
+        let hub = WsHub::new();
+        block_on(async {
+            let (a, _ca) = server.connect(&hub).await;
+            a.join("news").join("sports");
+            let mut rooms = hub.rooms();
+            rooms.sort();
+            assert_eq!(rooms, ["news", "sports"]);
+            a.leave("news");
+            assert_eq!(hub.rooms(), ["sports"]);
+            assert_eq!(a.rooms(), ["sports"]);
+            drop(a);
+            assert!(hub.rooms().is_empty());
+            let (mut b, cb) = server.connect(&hub).await;
+            b.join("news");
+            drop(cb);
+            assert!(b.next().await.is_none());
+            assert!(b.is_terminated());
+            assert!(hub.rooms().is_empty());
+            assert_eq!(hub.broadcast_text("news", "hello"), 0);
+        });
+    }
+
+    struct Woken(AtomicBool);
+
+    impl ArcWake for Woken {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn broadcast_wakes_subscriber() {
+        let mut server = Server::new();
+        let hub = WsHub::new();
+        let (mut subscriber, stream) = block_on(server.connect(&hub));
+        let mut client = client(stream);
+        subscriber.join("room");
+        let woken = Arc::new(Woken(AtomicBool::new(false)));
+        let waker = waker(woken.clone());
+        let mut cx = Context::from_waker(&waker);
+        assert!(subscriber.poll_next_unpin(&mut cx).is_pending());
+        assert!(!woken.0.load(Ordering::SeqCst));
+        assert_eq!(hub.broadcast_text("room", "hello"), 1);
+        assert!(woken.0.load(Ordering::SeqCst));
+        block_on(async {
+            let (_, received) = futures::join!(drive(&mut subscriber), receive(&mut client));
+            assert_eq!(received.as_deref(), Some("hello"));
+        });
+    }
+}
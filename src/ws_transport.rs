@@ -0,0 +1,1156 @@
+use crate::ws_config::DeflateParams;
+use crate::ws_heartbeat::Heartbeat;
+use crate::{WsConfig, WsLatency};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use futures::prelude::*;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+const FIN: u8 = 0x80;
+const RSV1: u8 = 0x40;
+const RSV: u8 = 0x70;
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_PONG: u8 = 0xa;
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+// Close with status 1002, for unmasked frames and undecodable compressed messages.
+const CLOSE_PROTOCOL_ERROR: [u8; 4] = [0x88, 0x02, 0x03, 0xea];
+// Close with status 1009, for frames and messages exceeding the configured limits.
+const CLOSE_TOO_BIG: [u8; 4] = [0x88, 0x02, 0x03, 0xf1];
+// Close with status 1008, for connections closed through a CloseHandle.
+const CLOSE_POLICY: [u8; 4] = [0x88, 0x02, 0x03, 0xf0];
+// Close with status 1011, as the connection is unusable without a responsive peer.
+const CLOSE_TIMEOUT: [u8; 4] = [0x88, 0x02, 0x03, 0xf3];
+const HEAD_END: &[u8] = b"\r\n\r\n";
+const CHUNK: usize = 8192;
+
+/// Transport of a websocket connection upgraded with a [WsConfig]. It follows the frames in both
+/// directions to enforce the size limits and to compress and decompress messages if
+/// `permessage-deflate` has been negotiated, and sends the pings of the
+/// [WsHeartbeat](crate::WsHeartbeat) between frames.
+///
+/// Frames violating the configuration fail the connection with [io::ErrorKind::InvalidData] after
+/// sending a close frame with status `1009` for exceeded limits or `1002` otherwise.
+pub struct WsTransport<IO> {
+    transport: IO,
+    head_end: usize,
+    incoming: Incoming,
+    outgoing: Outgoing,
+    read_buf: Vec<u8>,
+    pending: Vec<u8>,
+    heartbeat: Option<Heartbeat>,
+    close: CloseHandle,
+    failure: Option<Failure>,
+}
+
+/// Handle to fail a [WsTransport] with a close frame with status `1008` once it is read next,
+/// e.g. for a [WsHub](crate::WsHub) to disconnect slow subscribers.
+#[derive(Clone, Default)]
+pub(crate) struct CloseHandle(Arc<AtomicBool>);
+
+impl CloseHandle {
+    pub(crate) fn close(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+    fn is_closed(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> WsTransport<IO> {
+    /// Wrap a transport on which the `101 Switching Protocols` response is yet to be written.
+    pub(crate) fn new(transport: IO, config: &WsConfig, deflate: Option<DeflateParams>) -> Self {
+        WsTransport {
+            transport,
+            head_end: 0,
+            incoming: Incoming {
+                require_mask: config.require_mask,
+                max_frame_size: config.max_frame_size,
+                max_message_size: config.max_message_size,
+                inflate: deflate.map(|params| {
+                    // The inflater needs a window of at least 512 bytes, which also fits data
+                    // compressed with a window of 256 bytes.
+                    Decompress::new_with_window_bits(false, params.client_window_bits.max(9))
+                }),
+                reset_inflate: deflate.is_some_and(|params| params.client_no_context_takeover),
+                ..Incoming::default()
+            },
+            outgoing: Outgoing {
+                deflate: deflate.map(|params| {
+                    Compress::new_with_window_bits(
+                        Compression::new(params.level),
+                        false,
+                        params.server_window_bits,
+                    )
+                }),
+                reset_deflate: deflate.is_some_and(|params| params.server_no_context_takeover),
+                ..Outgoing::default()
+            },
+            read_buf: vec![0; CHUNK],
+            pending: Vec::new(),
+            heartbeat: config.heartbeat.map(Heartbeat::new),
+            close: CloseHandle::default(),
+            failure: None,
+        }
+    }
+    pub fn get_ref(&self) -> &IO {
+        &self.transport
+    }
+    /// Whether `permessage-deflate` has been negotiated.
+    pub fn is_compressed(&self) -> bool {
+        self.outgoing.deflate.is_some()
+    }
+    /// Handle reporting the round-trip time of the heartbeat pings, which stays empty without a
+    /// configured [WsHeartbeat](crate::WsHeartbeat).
+    pub fn latency(&self) -> WsLatency {
+        match &self.heartbeat {
+            Some(heartbeat) => heartbeat.latency(),
+            None => WsLatency::default(),
+        }
+    }
+    pub(crate) fn close_handle(&self) -> CloseHandle {
+        self.close.clone()
+    }
+    fn at_boundary(&self) -> bool {
+        self.head_end == HEAD_END.len() && self.outgoing.at_boundary()
+    }
+    fn poll_heartbeat(&mut self, cx: &mut Context<'_>) -> Result<(), Failure> {
+        let heartbeat = match &mut self.heartbeat {
+            Some(heartbeat) => heartbeat,
+            None => return Ok(()),
+        };
+        if heartbeat.poll_timeout(cx).is_ready() {
+            return Err(Failure::Unresponsive);
+        }
+        self.queue_ping();
+        Ok(())
+    }
+    /// Queue a due ping unless a frame is in progress, in which case it follows the frame.
+    fn queue_ping(&mut self) {
+        if !self.at_boundary() {
+            return;
+        }
+        if let Some(heartbeat) = &mut self.heartbeat {
+            heartbeat.write_ping(&mut self.pending);
+        }
+    }
+    /// Write processed frames, keeping the remainder if the transport is not ready.
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            match Pin::new(&mut self.transport).poll_write(cx, &self.pending) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => drop(self.pending.drain(..n)),
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+    fn fail(&mut self, cx: &mut Context<'_>, failure: Failure) -> io::Error {
+        log::debug!("failing websocket connection: {}", failure.message());
+        // A close frame can only be inserted between frames.
+        if self.at_boundary() {
+            self.pending.extend_from_slice(failure.close_frame());
+            let _ = self.poll_pending(cx);
+        }
+        self.failure = Some(failure);
+        failure.error()
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsTransport<IO> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if let Some(failure) = this.failure {
+            return Poll::Ready(Err(failure.error()));
+        }
+        if this.close.is_closed() {
+            return Poll::Ready(Err(this.fail(cx, Failure::Closed)));
+        }
+        if let Err(failure) = this.poll_heartbeat(cx) {
+            return Poll::Ready(Err(this.fail(cx, failure)));
+        }
+        if let Poll::Ready(Err(err)) = this.poll_pending(cx) {
+            return Poll::Ready(Err(err));
+        }
+        loop {
+            if let Some(failure) = this.failure {
+                return Poll::Ready(Err(failure.error()));
+            }
+            let n = this.incoming.read_output(buf);
+            if n > 0 || buf.is_empty() {
+                return Poll::Ready(Ok(n));
+            }
+            let n = match Pin::new(&mut this.transport).poll_read(cx, &mut this.read_buf) {
+                Poll::Ready(Ok(n)) => n,
+                p => return p,
+            };
+            if n == 0 {
+                return Poll::Ready(Ok(0));
+            }
+            if let Err(failure) = this.incoming.process(&this.read_buf[..n]) {
+                return Poll::Ready(Err(this.fail(cx, failure)));
+            }
+            if let Some(heartbeat) = &mut this.heartbeat {
+                heartbeat.received();
+                for pong in this.incoming.pongs.drain(..) {
+                    heartbeat.received_pong(&pong);
+                }
+            }
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsTransport<IO> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        match this.poll_pending(cx) {
+            Poll::Ready(Ok(())) => {}
+            p => return p.map(|r| r.map(|()| 0)),
+        }
+        let mut data = buf;
+        while this.head_end < HEAD_END.len() && !data.is_empty() {
+            this.head_end = match data[0] {
+                b if b == HEAD_END[this.head_end] => this.head_end + 1,
+                b'\r' => 1,
+                _ => 0,
+            };
+            this.pending.push(data[0]);
+            data = &data[1..];
+        }
+        this.outgoing.process(data, &mut this.pending)?;
+        this.queue_ping();
+        match this.poll_pending(cx) {
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            _ => Poll::Ready(Ok(buf.len())),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.poll_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.transport).poll_flush(cx),
+            p => p,
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.poll_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.transport).poll_close(cx),
+            p => p,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Failure {
+    Unmasked,
+    TooBig,
+    Corrupt,
+    Unresponsive,
+    Closed,
+}
+
+impl Failure {
+    fn message(self) -> &'static str {
+        match self {
+            Failure::Unmasked => "received unmasked websocket frame",
+            Failure::TooBig => "websocket frame or message exceeds size limit",
+            Failure::Corrupt => "received invalid compressed websocket message",
+            Failure::Unresponsive => "websocket heartbeat timed out",
+            Failure::Closed => "websocket connection closed by the server",
+        }
+    }
+    fn close_frame(self) -> &'static [u8] {
+        match self {
+            Failure::TooBig => &CLOSE_TOO_BIG,
+            Failure::Unmasked | Failure::Corrupt => &CLOSE_PROTOCOL_ERROR,
+            Failure::Unresponsive => &CLOSE_TIMEOUT,
+            Failure::Closed => &CLOSE_POLICY,
+        }
+    }
+    fn error(self) -> io::Error {
+        let kind = match self {
+            Failure::Unresponsive => io::ErrorKind::TimedOut,
+            Failure::Closed => io::ErrorKind::ConnectionAborted,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, self.message())
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct FrameHead {
+    first: u8,
+    mask: Option<[u8; 4]>,
+    len: u64,
+}
+
+impl FrameHead {
+    /// Parse a frame head, returning `None` while it is incomplete.
+    fn parse(head: &[u8]) -> Option<FrameHead> {
+        if head.len() < 2 {
+            return None;
+        }
+        let extended_len = match head[1] & 0x7f {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+        let mask_len = match head[1] & 0x80 {
+            0 => 0,
+            _ => 4,
+        };
+        if head.len() < 2 + extended_len + mask_len {
+            return None;
+        }
+        let len = match extended_len {
+            2 => u16::from_be_bytes([head[2], head[3]]) as u64,
+            8 => {
+                let mut len = [0u8; 8];
+                len.copy_from_slice(&head[2..10]);
+                u64::from_be_bytes(len)
+            }
+            _ => (head[1] & 0x7f) as u64,
+        };
+        let mask = match mask_len {
+            0 => None,
+            _ => {
+                let mut mask = [0u8; 4];
+                mask.copy_from_slice(&head[2 + extended_len..]);
+                Some(mask)
+            }
+        };
+        Some(FrameHead {
+            first: head[0],
+            mask,
+            len,
+        })
+    }
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(self.first);
+        let mask_bit = match self.mask {
+            Some(_) => 0x80,
+            None => 0,
+        };
+        match self.len {
+            len @ 0..=125 => out.push(mask_bit | len as u8),
+            len @ 126..=0xffff => {
+                out.push(mask_bit | 126);
+                out.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                out.push(mask_bit | 127);
+                out.extend_from_slice(&len.to_be_bytes());
+            }
+        }
+        if let Some(mask) = self.mask {
+            out.extend_from_slice(&mask);
+        }
+    }
+    fn fin(&self) -> bool {
+        self.first & FIN != 0
+    }
+    fn opcode(&self) -> u8 {
+        self.first & 0x0f
+    }
+    fn is_control(&self) -> bool {
+        self.first & 0x08 != 0
+    }
+}
+
+fn apply_mask(data: &mut [u8], mask: [u8; 4], offset: u64) {
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= mask[((offset + i as u64) % 4) as usize];
+    }
+}
+
+/// Frame whose payload is being received or sent.
+struct Frame {
+    head: FrameHead,
+    remaining: u64,
+    /// Whether the payload is decompressed or compressed instead of being passed on.
+    deflate: bool,
+}
+
+/// Data message being received.
+struct Message {
+    opcode: u8,
+    len: u64,
+    compressed: bool,
+    started: bool,
+}
+
+/// Checks received frames, decompressing messages into plain frames for the connection.
+#[derive(Default)]
+struct Incoming {
+    require_mask: bool,
+    max_frame_size: Option<u64>,
+    max_message_size: Option<u64>,
+    head: Vec<u8>,
+    frame: Option<Frame>,
+    offset: u64,
+    message: Option<Message>,
+    inflate: Option<Decompress>,
+    reset_inflate: bool,
+    stream_end: bool,
+    output: Vec<u8>,
+    output_pos: usize,
+    pong: Vec<u8>,
+    /// Unmasked payloads of the pongs received since last taken.
+    pongs: Vec<Vec<u8>>,
+}
+
+impl Incoming {
+    fn read_output(&mut self, buf: &mut [u8]) -> usize {
+        let available = &self.output[self.output_pos..];
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.output_pos += n;
+        if self.output_pos == self.output.len() {
+            self.output.clear();
+            self.output_pos = 0;
+        }
+        n
+    }
+    fn process(&mut self, mut data: &[u8]) -> Result<(), Failure> {
+        while !data.is_empty() {
+            let frame = match &mut self.frame {
+                Some(frame) => frame,
+                None => {
+                    self.head.push(data[0]);
+                    data = &data[1..];
+                    if let Some(head) = FrameHead::parse(&self.head) {
+                        self.start_frame(head)?;
+                    }
+                    continue;
+                }
+            };
+            let n = frame.remaining.min(data.len() as u64) as usize;
+            frame.remaining -= n as u64;
+            let (deflate, mask, opcode) = (frame.deflate, frame.head.mask, frame.head.opcode());
+            let (payload, rest) = data.split_at(n);
+            data = rest;
+            match deflate {
+                true => {
+                    let mut payload = payload.to_vec();
+                    if let Some(mask) = mask {
+                        apply_mask(&mut payload, mask, self.offset);
+                    }
+                    self.offset += n as u64;
+                    self.inflate(&payload)?;
+                }
+                false => {
+                    if opcode == OPCODE_PONG {
+                        let offset = self.pong.len();
+                        self.pong.extend_from_slice(payload);
+                        if let Some(mask) = mask {
+                            apply_mask(&mut self.pong[offset..], mask, offset as u64);
+                        }
+                    }
+                    self.output.extend_from_slice(payload)
+                }
+            }
+            if matches!(&self.frame, Some(frame) if frame.remaining == 0) {
+                self.end_frame()?;
+            }
+        }
+        Ok(())
+    }
+    fn start_frame(&mut self, head: FrameHead) -> Result<(), Failure> {
+        if self.require_mask && head.mask.is_none() {
+            return Err(Failure::Unmasked);
+        }
+        let mut deflate = false;
+        if !head.is_control() {
+            if matches!(self.max_frame_size, Some(max) if head.len > max) {
+                return Err(Failure::TooBig);
+            }
+            if head.opcode() != OPCODE_CONTINUATION {
+                self.message = Some(Message {
+                    opcode: head.opcode(),
+                    len: 0,
+                    compressed: head.first & RSV1 != 0 && self.inflate.is_some(),
+                    started: false,
+                });
+            }
+            if let Some(message) = &mut self.message {
+                // Frames with unexpected reserved bits are left for the connection to reject.
+                let rsv = match head.opcode() {
+                    OPCODE_CONTINUATION => 0,
+                    _ => RSV1,
+                };
+                deflate = message.compressed && head.first & RSV == rsv;
+                if !deflate {
+                    message.len += head.len;
+                    if matches!(self.max_message_size, Some(max) if message.len > max) {
+                        return Err(Failure::TooBig);
+                    }
+                }
+            }
+        }
+        if !deflate {
+            self.output.extend_from_slice(&self.head);
+        }
+        self.head.clear();
+        self.offset = 0;
+        self.frame = Some(Frame {
+            head,
+            remaining: head.len,
+            deflate,
+        });
+        if head.len == 0 {
+            self.end_frame()?;
+        }
+        Ok(())
+    }
+    fn end_frame(&mut self) -> Result<(), Failure> {
+        let frame = match self.frame.take() {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        if frame.head.opcode() == OPCODE_PONG {
+            self.pongs.push(std::mem::take(&mut self.pong));
+        }
+        if frame.head.is_control() || !frame.head.fin() {
+            return Ok(());
+        }
+        if frame.deflate {
+            self.inflate(&DEFLATE_TAIL)?;
+            self.emit(&[], true);
+            if self.reset_inflate || self.stream_end {
+                if let Some(inflate) = &mut self.inflate {
+                    inflate.reset(false);
+                }
+                self.stream_end = false;
+            }
+        }
+        self.message = None;
+        Ok(())
+    }
+    fn inflate(&mut self, mut input: &[u8]) -> Result<(), Failure> {
+        let mut chunk = [0u8; CHUNK];
+        loop {
+            let inflate = match &mut self.inflate {
+                Some(inflate) => inflate,
+                None => return Ok(()),
+            };
+            let (total_in, total_out) = (inflate.total_in(), inflate.total_out());
+            let status = inflate
+                .decompress(input, &mut chunk, FlushDecompress::None)
+                .map_err(|_| Failure::Corrupt)?;
+            let consumed = (inflate.total_in() - total_in) as usize;
+            let produced = (inflate.total_out() - total_out) as usize;
+            input = &input[consumed..];
+            self.stream_end |= status == Status::StreamEnd;
+            if produced > 0 {
+                if let Some(message) = &mut self.message {
+                    message.len += produced as u64;
+                    if matches!(self.max_message_size, Some(max) if message.len > max) {
+                        return Err(Failure::TooBig);
+                    }
+                }
+                self.emit(&chunk[..produced], false);
+            }
+            if (input.is_empty() && produced < CHUNK) || (consumed == 0 && produced == 0) {
+                return Ok(());
+            }
+        }
+    }
+    /// Pass decompressed data on as an unmasked frame without reserved bits.
+    fn emit(&mut self, payload: &[u8], fin: bool) {
+        let message = match &mut self.message {
+            Some(message) => message,
+            None => return,
+        };
+        let opcode = match message.started {
+            true => OPCODE_CONTINUATION,
+            false => message.opcode,
+        };
+        message.started = true;
+        let fin = match fin {
+            true => FIN,
+            false => 0,
+        };
+        let head = FrameHead {
+            first: fin | opcode,
+            mask: None,
+            len: payload.len() as u64,
+        };
+        head.encode(&mut self.output);
+        self.output.extend_from_slice(payload);
+    }
+}
+
+/// Follows the frames written by the connection, compressing data frames.
+#[derive(Default)]
+struct Outgoing {
+    head: Vec<u8>,
+    frame: Option<Frame>,
+    payload: Vec<u8>,
+    deflate: Option<Compress>,
+    reset_deflate: bool,
+}
+
+impl Outgoing {
+    fn at_boundary(&self) -> bool {
+        self.head.is_empty() && self.frame.is_none()
+    }
+    fn process(&mut self, mut data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        while !data.is_empty() {
+            let frame = match &mut self.frame {
+                Some(frame) => frame,
+                None => {
+                    self.head.push(data[0]);
+                    data = &data[1..];
+                    if let Some(head) = FrameHead::parse(&self.head) {
+                        let deflate =
+                            self.deflate.is_some() && !head.is_control() && head.first & RSV == 0;
+                        if !deflate {
+                            out.extend_from_slice(&self.head);
+                        }
+                        self.head.clear();
+                        self.frame = Some(Frame {
+                            head,
+                            remaining: head.len,
+                            deflate,
+                        });
+                        if head.len == 0 {
+                            self.end_frame(out)?;
+                        }
+                    }
+                    continue;
+                }
+            };
+            let n = frame.remaining.min(data.len() as u64) as usize;
+            frame.remaining -= n as u64;
+            match frame.deflate {
+                true => self.payload.extend_from_slice(&data[..n]),
+                false => out.extend_from_slice(&data[..n]),
+            }
+            data = &data[n..];
+            if matches!(&self.frame, Some(frame) if frame.remaining == 0) {
+                self.end_frame(out)?;
+            }
+        }
+        Ok(())
+    }
+    fn end_frame(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        let frame = match self.frame.take() {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        if !frame.deflate {
+            return Ok(());
+        }
+        let mut payload = std::mem::take(&mut self.payload);
+        if let Some(mask) = frame.head.mask {
+            apply_mask(&mut payload, mask, 0);
+        }
+        let mut compressed = self.deflate(&payload, frame.head.fin())?;
+        payload.clear();
+        self.payload = payload;
+        if let Some(mask) = frame.head.mask {
+            apply_mask(&mut compressed, mask, 0);
+        }
+        // Only the first frame of a compressed message is marked.
+        let rsv = match frame.head.opcode() {
+            OPCODE_CONTINUATION => 0,
+            _ => RSV1,
+        };
+        let head = FrameHead {
+            first: frame.head.first | rsv,
+            mask: frame.head.mask,
+            len: compressed.len() as u64,
+        };
+        head.encode(out);
+        out.extend_from_slice(&compressed);
+        Ok(())
+    }
+    fn deflate(&mut self, mut input: &[u8], fin: bool) -> io::Result<Vec<u8>> {
+        let deflate = match &mut self.deflate {
+            Some(deflate) => deflate,
+            None => return Ok(input.to_vec()),
+        };
+        let flush = match fin {
+            true => FlushCompress::Sync,
+            false => FlushCompress::None,
+        };
+        let mut output = Vec::with_capacity(input.len() / 2 + 64);
+        loop {
+            let total_in = deflate.total_in();
+            deflate
+                .compress_vec(input, &mut output, flush)
+                .map_err(io::Error::other)?;
+            input = &input[(deflate.total_in() - total_in) as usize..];
+            if input.is_empty() && output.len() < output.capacity() {
+                break;
+            }
+            output.reserve(CHUNK);
+        }
+        if fin {
+            if output.ends_with(&DEFLATE_TAIL) {
+                output.truncate(output.len() - DEFLATE_TAIL.len());
+            }
+            if self.reset_deflate {
+                deflate.reset();
+            }
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{WsHeartbeat, WsMessageKind};
+    use async_io::Timer;
+    use async_ws::connection::WsConnection;
+    use futures::executor::block_on;
+    use futures::future::{select, Either};
+    use futures::io::Cursor;
+    use std::time::Duration;
+
+    struct MockTransport {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+        /// Keep the connection open after the input instead of signalling the end of the stream.
+        open: bool,
+    }
+
+    impl AsyncRead for MockTransport {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            match Pin::new(&mut self.input).poll_read(cx, buf) {
+                Poll::Ready(Ok(0)) if self.open => Poll::Pending,
+                p => p,
+            }
+        }
+    }
+
+    impl AsyncWrite for MockTransport {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.output.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    const MASK: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+    fn params() -> DeflateParams {
+        DeflateParams {
+            level: 6,
+            server_window_bits: 15,
+            client_window_bits: 15,
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+        }
+    }
+
+    fn wrap(
+        input: Vec<u8>,
+        config: WsConfig,
+        deflate: Option<DeflateParams>,
+    ) -> WsTransport<MockTransport> {
+        let mut transport = WsTransport::new(
+            MockTransport {
+                input: Cursor::new(input),
+                output: Vec::new(),
+                open: false,
+            },
+            &config,
+            deflate,
+        );
+        block_on(transport.write_all(b"HTTP/1.1 101 Switching Protocols\r\n\r\n")).unwrap();
+        transport.transport.output.clear();
+        transport
+    }
+
+    /// Encode a frame as sent by a client.
+    fn frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let head = FrameHead {
+            first,
+            mask: Some(MASK),
+            len: payload.len() as u64,
+        };
+        head.encode(&mut out);
+        let mut payload = payload.to_vec();
+        apply_mask(&mut payload, MASK, 0);
+        out.extend_from_slice(&payload);
+        out
+    }
+
+    /// Decode frames, returning their first bytes and unmasked payloads.
+    fn frames(mut data: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut frames = Vec::new();
+        while !data.is_empty() {
+            let len = (2..=14)
+                .find(|&len| FrameHead::parse(&data[..len.min(data.len())]).is_some())
+                .unwrap();
+            let head = FrameHead::parse(&data[..len]).unwrap();
+            let mut payload = data[len..len + head.len as usize].to_vec();
+            if let Some(mask) = head.mask {
+                apply_mask(&mut payload, mask, 0);
+            }
+            frames.push((head.first, payload));
+            data = &data[len + head.len as usize..];
+        }
+        frames
+    }
+
+    fn compress(compress: &mut Compress, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + 64);
+        compress
+            .compress_vec(data, &mut out, FlushCompress::Sync)
+            .unwrap();
+        assert!(out.ends_with(&DEFLATE_TAIL));
+        out.truncate(out.len() - 4);
+        out
+    }
+
+    fn decompress(decompress: &mut Decompress, data: &[u8]) -> Vec<u8> {
+        let mut input = data.to_vec();
+        input.extend_from_slice(&DEFLATE_TAIL);
+        let mut out = Vec::with_capacity(1 << 20);
+        decompress
+            .decompress_vec(&input, &mut out, FlushDecompress::Sync)
+            .unwrap();
+        out
+    }
+
+    fn read_all(transport: &mut WsTransport<MockTransport>) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        block_on(transport.read_to_end(&mut out))?;
+        Ok(out)
+    }
+
+    /// Read until data arrives or `wait` has passed, giving the heartbeat time to act.
+    fn read_for(
+        transport: &mut WsTransport<MockTransport>,
+        wait: Duration,
+    ) -> Option<io::Result<usize>> {
+        let mut buf = [0u8; 1024];
+        block_on(async {
+            match select(transport.read(&mut buf), Timer::after(wait)).await {
+                Either::Left((result, _)) => Some(result),
+                Either::Right(_) => None,
+            }
+        })
+    }
+
+    fn heartbeat(interval: u64, timeout: u64) -> WsTransport<MockTransport> {
+        let heartbeat = WsHeartbeat::new(
+            Duration::from_millis(interval),
+            Duration::from_millis(timeout),
+        );
+        let mut transport = wrap(Vec::new(), WsConfig::server().heartbeat(heartbeat), None);
+        transport.transport.open = true;
+        transport
+    }
+
+    /// Reassemble the data messages in a sequence of frames.
+    fn messages(frames: &[(u8, Vec<u8>)]) -> Vec<(u8, Vec<u8>)> {
+        let mut messages: Vec<(u8, Vec<u8>)> = Vec::new();
+        let mut open = false;
+        for (first, payload) in frames {
+            assert_eq!(first & RSV, 0);
+            match open {
+                true => {
+                    assert_eq!(first & 0x0f, OPCODE_CONTINUATION);
+                    messages.last_mut().unwrap().1.extend_from_slice(payload);
+                }
+                false => messages.push((first & 0x0f, payload.clone())),
+            }
+            open = first & FIN == 0;
+        }
+        messages
+    }
+
+    #[test]
+    fn frames_pass_through() {
+        let mut input = frame(0x01, b"hel");
+        input.extend(frame(0x89, b"ping"));
+        input.extend(frame(0x80, b"lo"));
+        input.extend(frame(0x82, &[7; 300]));
+        let mut transport = wrap(input.clone(), WsConfig::server(), None);
+        assert_eq!(read_all(&mut transport).unwrap(), input);
+        let output = frame(0x81, b"hello");
+        block_on(transport.write_all(&output)).unwrap();
+        assert_eq!(transport.get_ref().output, output);
+    }
+
+    #[test]
+    fn inflate_messages() {
+        let mut client = Compress::new_with_window_bits(Compression::default(), false, 15);
+        let first = "a chatty json message ".repeat(100);
+        let second = "another chatty json message ".repeat(100);
+        let first_compressed = compress(&mut client, first.as_bytes());
+        // The second message refers to the first one, as context takeover is allowed.
+        let second_compressed = compress(&mut client, second.as_bytes());
+        let (a, b) = second_compressed.split_at(second_compressed.len() / 2);
+        let mut input = frame(0xc1, &first_compressed);
+        input.extend(frame(0x42, a));
+        input.extend(frame(0x89, b"ping"));
+        input.extend(frame(0x80, b));
+        input.extend(frame(0x81, b"plain"));
+        let mut transport = wrap(input, WsConfig::server(), Some(params()));
+        let output = frames(&read_all(&mut transport).unwrap());
+        assert!(output.contains(&(0x89, b"ping".to_vec())));
+        let data = output
+            .into_iter()
+            .filter(|(first, _)| first & 0x08 == 0)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages(&data),
+            vec![
+                (0x1, first.into_bytes()),
+                (0x2, second.into_bytes()),
+                (0x1, b"plain".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn deflate_messages() {
+        let mut transport = wrap(Vec::new(), WsConfig::server(), Some(params()));
+        let message = "a chatty json message ".repeat(100);
+        let (a, b) = message.as_bytes().split_at(1000);
+        let mut written = Vec::new();
+        FrameHead {
+            first: 0x01,
+            mask: None,
+            len: a.len() as u64,
+        }
+        .encode(&mut written);
+        written.extend_from_slice(a);
+        written.extend_from_slice(&[0x89, 0x00]);
+        FrameHead {
+            first: 0x80,
+            mask: None,
+            len: b.len() as u64,
+        }
+        .encode(&mut written);
+        written.extend_from_slice(b);
+        written.extend_from_slice(&[0x82, 0x00]);
+        // Written in small pieces, as the connection may split frames across writes.
+        for chunk in written.chunks(7) {
+            block_on(transport.write_all(chunk)).unwrap();
+        }
+        let output = frames(&transport.get_ref().output);
+        assert_eq!(output.len(), 4);
+        assert_eq!(output[0].0, 0x41);
+        assert_eq!(output[1], (0x89, Vec::new()));
+        assert_eq!(output[2].0, 0x80);
+        assert_eq!(output[3].0, 0xc2);
+        assert!(output[0].1.len() + output[2].1.len() < message.len() / 10);
+        let mut client = Decompress::new_with_window_bits(false, 15);
+        let mut compressed = output[0].1.clone();
+        compressed.extend_from_slice(&output[2].1);
+        assert_eq!(decompress(&mut client, &compressed), message.as_bytes());
+        assert_eq!(decompress(&mut client, &output[3].1), b"");
+    }
+
+    #[test]
+    fn no_context_takeover() {
+        let params = DeflateParams {
+            server_no_context_takeover: true,
+            client_no_context_takeover: true,
+            ..params()
+        };
+        let message = "a chatty json message ".repeat(10);
+        let mut input = Vec::new();
+        for _ in 0..2 {
+            let mut client = Compress::new_with_window_bits(Compression::default(), false, 15);
+            input.extend(frame(0xc1, &compress(&mut client, message.as_bytes())));
+        }
+        let mut transport = wrap(input, WsConfig::server(), Some(params));
+        let output = frames(&read_all(&mut transport).unwrap());
+        assert_eq!(
+            messages(&output),
+            vec![
+                (0x1, message.clone().into_bytes()),
+                (0x1, message.clone().into_bytes())
+            ]
+        );
+        let mut written = Vec::new();
+        for _ in 0..2 {
+            FrameHead {
+                first: 0x81,
+                mask: None,
+                len: message.len() as u64,
+            }
+            .encode(&mut written);
+            written.extend_from_slice(message.as_bytes());
+        }
+        block_on(transport.write_all(&written)).unwrap();
+        let output = frames(&transport.get_ref().output);
+        assert_eq!(output.len(), 2);
+        for (_, compressed) in output {
+            let mut client = Decompress::new_with_window_bits(false, 15);
+            assert_eq!(decompress(&mut client, &compressed), message.as_bytes());
+        }
+    }
+
+    #[test]
+    fn frame_size_limit() {
+        let config = WsConfig::server().max_frame_size(100);
+        let mut input = frame(0x81, &[b'a'; 100]);
+        input.extend(frame(0x81, &[b'a'; 101]));
+        let mut transport = wrap(input, config, None);
+        let err = read_all(&mut transport).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(transport.get_ref().output, CLOSE_TOO_BIG);
+    }
+
+    #[test]
+    fn message_size_limit() {
+        let config = WsConfig::server().max_message_size(100);
+        let mut input = frame(0x01, &[b'a'; 60]);
+        input.extend(frame(0x89, &[0; 60]));
+        input.extend(frame(0x80, &[b'a'; 40]));
+        input.extend(frame(0x01, &[b'a'; 60]));
+        let mut transport = wrap(input.clone(), config, None);
+        assert_eq!(read_all(&mut transport).unwrap(), input);
+        input.extend(frame(0x80, &[b'a'; 41]));
+        let mut transport = wrap(input, config, None);
+        assert!(read_all(&mut transport).is_err());
+        assert_eq!(transport.get_ref().output, CLOSE_TOO_BIG);
+    }
+
+    #[test]
+    fn decompressed_size_limit() {
+        let config = WsConfig::server().max_message_size(100_000);
+        let mut client = Compress::new_with_window_bits(Compression::best(), false, 15);
+        let input = frame(0xc2, &compress(&mut client, &[0; 100_001]));
+        assert!(input.len() < 1000);
+        let mut transport = wrap(input, config, Some(params()));
+        assert!(read_all(&mut transport).is_err());
+        assert_eq!(transport.get_ref().output, CLOSE_TOO_BIG);
+    }
+
+    #[test]
+    fn unmasked_frames() {
+        let input = vec![0x81, 0x02, b'h', b'i'];
+        let mut transport = wrap(input.clone(), WsConfig::server(), None);
+        assert!(read_all(&mut transport).is_err());
+        assert_eq!(transport.get_ref().output, CLOSE_PROTOCOL_ERROR);
+        let config = WsConfig::server().require_mask(false);
+        let mut transport = wrap(input.clone(), config, None);
+        assert_eq!(read_all(&mut transport).unwrap(), input);
+    }
+
+    #[test]
+    fn corrupt_compressed_message() {
+        let input = frame(0xc1, &[0xff; 16]);
+        let mut transport = wrap(input, WsConfig::server(), Some(params()));
+        assert!(read_all(&mut transport).is_err());
+        assert_eq!(transport.get_ref().output, CLOSE_PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn close_waits_for_frame_boundary() {
+        let config = WsConfig::server().max_frame_size(10);
+        let mut transport = wrap(frame(0x81, &[b'a'; 11]), config, None);
+        block_on(transport.write_all(&[0x81, 0x05, b'h'])).unwrap();
+        assert!(read_all(&mut transport).is_err());
+        assert_eq!(transport.get_ref().output, [0x81, 0x05, b'h']);
+    }
+
+    #[test]
+    fn compressed_connection() {
+        let mut client = Compress::new_with_window_bits(Compression::default(), false, 15);
+        let message = "{\"event\":\"update\",\"value\":42}".repeat(100);
+        let input = frame(0xc1, &compress(&mut client, message.as_bytes()));
+        let mut transport = wrap(input, WsConfig::server(), Some(params()));
+        transport.transport.open = true;
+        let mut ws = WsConnection::with_config(transport, WsConfig::server().connection_config());
+        block_on(async {
+            let mut reader = ws.next().await.unwrap();
+            assert!(matches!(reader.kind(), WsMessageKind::Text));
+            let mut received = String::new();
+            reader.read_to_string(&mut received).await.unwrap();
+            assert_eq!(received, message);
+            let mut writer = ws.send(WsMessageKind::Text).await.unwrap();
+            writer.write_all(message.as_bytes()).await.unwrap();
+            writer.close().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn heartbeat_timeout() {
+        let mut transport = heartbeat(20, 20);
+        let err = read_for(&mut transport, Duration::from_secs(1))
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(
+            frames(&transport.get_ref().output),
+            vec![
+                (0x89, 1u64.to_be_bytes().to_vec()),
+                (0x88, CLOSE_TIMEOUT[2..].to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn heartbeat_latency() {
+        let mut transport = heartbeat(100, 10_000);
+        let latency = transport.latency();
+        assert!(read_for(&mut transport, Duration::from_millis(150)).is_none());
+        assert_eq!(
+            frames(&transport.get_ref().output),
+            vec![(0x89, 1u64.to_be_bytes().to_vec())]
+        );
+        // Pongs not answering the heartbeat ping, e.g. unsolicited ones, are no latency sample.
+        for payload in [&[][..], &2u64.to_be_bytes()] {
+            let pong = frame(0x8a, payload);
+            transport.transport.input.get_mut().extend_from_slice(&pong);
+            let n = read_for(&mut transport, Duration::from_secs(1)).unwrap();
+            assert_eq!(n.unwrap(), pong.len());
+            assert_eq!(latency.get(), None);
+        }
+        let pong = frame(0x8a, &1u64.to_be_bytes());
+        transport.transport.input.get_mut().extend_from_slice(&pong);
+        assert!(read_for(&mut transport, Duration::from_secs(1)).is_some());
+        assert!(latency.get().unwrap() >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn heartbeat_waits_for_frame_boundary() {
+        let mut transport = heartbeat(20, 10_000);
+        block_on(transport.write_all(&[0x81, 0x05, b'h'])).unwrap();
+        assert!(read_for(&mut transport, Duration::from_millis(50)).is_none());
+        assert_eq!(transport.get_ref().output, [0x81, 0x05, b'h']);
+        block_on(transport.write_all(b"ello")).unwrap();
+        assert_eq!(
+            frames(&transport.get_ref().output),
+            vec![
+                (0x81, b"hello".to_vec()),
+                (0x89, 1u64.to_be_bytes().to_vec())
+            ]
+        );
+    }
+}